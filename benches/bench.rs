@@ -1,13 +1,15 @@
-#![feature(test)]
-
-extern crate test;
+// The libtest harness needs nightly, so the benchmarks use `bencher`, which
+// has the same API but builds on stable.
+#[macro_use]
+extern crate bencher;
+extern crate libc;
 extern crate tinycdb;
 
-use std::fs;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
-use test::Bencher;
-use tinycdb::Cdb;
+use bencher::Bencher;
+use tinycdb::{Advice, Cdb, OpenOptions};
 
 
 // Helper to remove test files after a test is finished, even if the test
@@ -25,6 +27,7 @@ impl RemovingPath {
 }
 
 impl Drop for RemovingPath {
+    #[allow(clippy::single_match)]
     fn drop(&mut self) {
         match fs::remove_file(&self.underlying) {
             Err(why) => println!("Couldn't remove temp file: {:?}", why),
@@ -33,7 +36,7 @@ impl Drop for RemovingPath {
     }
 }
 
-#[bench]
+#[allow(clippy::needless_borrow)]
 fn bench_add(b: &mut Bencher) {
     use std::sync::atomic::{AtomicUsize, Ordering};
     let ctr = AtomicUsize::new(0);
@@ -55,7 +58,7 @@ fn bench_add(b: &mut Bencher) {
     });
 }
 
-#[bench]
+#[allow(clippy::needless_borrow)]
 fn bench_find(b: &mut Bencher) {
     let path = Path::new("find_bench.cdb");
     let _rem = RemovingPath::new(&path);
@@ -71,11 +74,11 @@ fn bench_find(b: &mut Bencher) {
     };
 
     b.iter(|| {
-        test::black_box(c.find(b"foo"));
+        bencher::black_box(c.find(b"foo"));
     });
 }
 
-#[bench]
+#[allow(clippy::needless_borrow)]
 fn bench_find_mut(b: &mut Bencher) {
     let path = Path::new("find_mut_bench.cdb");
     let _rem = RemovingPath::new(&path);
@@ -91,11 +94,11 @@ fn bench_find_mut(b: &mut Bencher) {
     };
 
     b.iter(|| {
-        test::black_box(c.find_mut(b"foo"));
+        bencher::black_box(c.find_mut(b"foo"));
     });
 }
 
-#[bench]
+#[allow(clippy::needless_borrow)]
 fn bench_exists(b: &mut Bencher) {
     let path = Path::new("exists_bench.cdb");
     let _rem = RemovingPath::new(&path);
@@ -111,7 +114,141 @@ fn bench_exists(b: &mut Bencher) {
     };
 
     b.iter(|| {
-        test::black_box(c.exists(b"foo"));
+        bencher::black_box(c.exists(b"foo"));
     });
 }
 
+// Creates a database with many records, so that lookups touch many different
+// pages of the file.
+fn create_large(path: &Path) -> Box<Cdb> {
+    let res = Cdb::new(path, |creator| {
+        for i in 0..100000 {
+            let key = format!("key{}", i);
+            let val = format!("val{}", i);
+            let r = creator.add(key.as_bytes(), val.as_bytes());
+            assert!(r.is_ok());
+        }
+    });
+
+    match res {
+        Ok(c) => c,
+        Err(why) => panic!("Could not create: {:?}", why),
+    }
+}
+
+fn batch_keys() -> Vec<String> {
+    (0..32).map(|i| format!("key{}", i * 3119)).collect()
+}
+
+fn bench_find_repeated(b: &mut Bencher) {
+    let path = Path::new("find_repeated_bench.cdb");
+    let _rem = RemovingPath::new(path);
+    let mut c = create_large(path);
+    let keys = batch_keys();
+
+    b.iter(|| {
+        for key in &keys {
+            bencher::black_box(c.find(key.as_bytes()));
+        }
+    });
+}
+
+fn bench_find_many(b: &mut Bencher) {
+    let path = Path::new("find_many_bench.cdb");
+    let _rem = RemovingPath::new(path);
+    let c = create_large(path);
+    let keys = batch_keys();
+    let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+
+    b.iter(|| {
+        bencher::black_box(c.find_many(&keys).unwrap());
+    });
+}
+
+// Drops the file's pages from the page cache, so the next lookups against a
+// freshly-opened database have to go to disk.  Only done on Linux; elsewhere
+// the cold benchmarks measure warm lookups.
+fn evict(path: &Path) {
+    let f = File::open(path).unwrap();
+    f.sync_all().unwrap();
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        unsafe {
+            libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
+
+fn bench_find_repeated_cold(b: &mut Bencher) {
+    let path = Path::new("find_repeated_cold_bench.cdb");
+    let _rem = RemovingPath::new(path);
+    drop(create_large(path));
+    let keys = batch_keys();
+
+    b.iter(|| {
+        evict(path);
+        let mut c = Cdb::open(path).unwrap();
+        for key in &keys {
+            bencher::black_box(c.find(key.as_bytes()));
+        }
+    });
+}
+
+fn bench_find_many_cold(b: &mut Bencher) {
+    let path = Path::new("find_many_cold_bench.cdb");
+    let _rem = RemovingPath::new(path);
+    drop(create_large(path));
+    let keys = batch_keys();
+    let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+
+    b.iter(|| {
+        evict(path);
+        let c = Cdb::open(path).unwrap();
+        bencher::black_box(c.find_many(&keys).unwrap());
+    });
+}
+
+// Opens a cold database with the given options and then looks up a batch of
+// keys, so the effect of the options on cold lookup latency can be compared.
+fn bench_open_find_cold(b: &mut Bencher, name: &str, opts: &OpenOptions) {
+    let path = Path::new(name);
+    let _rem = RemovingPath::new(path);
+    drop(create_large(path));
+    let keys = batch_keys();
+
+    b.iter(|| {
+        evict(path);
+        let mut c = Cdb::open_with(path, opts).unwrap();
+        for key in &keys {
+            bencher::black_box(c.find(key.as_bytes()));
+        }
+    });
+}
+
+fn bench_open_find_cold_default(b: &mut Bencher) {
+    bench_open_find_cold(b, "open_default_bench.cdb", &OpenOptions::new());
+}
+
+fn bench_open_find_cold_random(b: &mut Bencher) {
+    bench_open_find_cold(b, "open_random_bench.cdb", OpenOptions::new().advice(Advice::Random));
+}
+
+fn bench_open_find_cold_populate(b: &mut Bencher) {
+    bench_open_find_cold(b, "open_populate_bench.cdb", OpenOptions::new().populate(true));
+}
+
+benchmark_group!(benches,
+                 bench_add,
+                 bench_find,
+                 bench_find_mut,
+                 bench_exists,
+                 bench_find_repeated,
+                 bench_find_many,
+                 bench_find_repeated_cold,
+                 bench_find_many_cold,
+                 bench_open_find_cold_default,
+                 bench_open_find_cold_random,
+                 bench_open_find_cold_populate);
+benchmark_main!(benches);
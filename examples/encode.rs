@@ -1,6 +1,6 @@
-/// This is a simple helper utility to encode a cdb file for use in our tests.
-/// Pass it a filename as the first argument and it will DEFLATE and then
-/// Base64 encode the contents, writing the output to stdout.
+//! This is a simple helper utility to encode a cdb file for use in our tests.
+//! Pass it a filename as the first argument and it will DEFLATE and then
+//! Base64 encode the contents, writing the output to stdout.
 
 
 extern crate lz4;
@@ -15,14 +15,17 @@ use serialize::base64::{STANDARD, ToBase64};
 // NOTE: we have the attribute here to suppress irritating warnings when using
 // Cargo to compile/test/benchmark the remainder of this library.
 fn main() {
+    #[allow(clippy::iter_skip_next)]
     let fname = std::env::args().skip(1).next().unwrap();
 
+    #[allow(clippy::needless_borrows_for_generic_args)]
     let mut file = match File::open(&Path::new(&*fname)) {
         Err(why) => panic!("Couldn't open {}: {:?}", fname, why),
         Ok(file) => file,
     };
 
     let mut buf = Vec::new();
+    #[allow(clippy::single_match)]
     match file.read_to_end(&mut buf) {
         Err(why) => panic!("Couldn't read {}: {:?}", fname, why),
         Ok(_) => {},
@@ -31,16 +34,19 @@ fn main() {
     let mut compressed = Vec::new();
     {
         let mut encoder = lz4::EncoderBuilder::new().build(&mut compressed).unwrap();
+        #[allow(clippy::single_match, clippy::explicit_auto_deref)]
         match encoder.write_all(&*buf) {
             Err(why) => panic!("Could not compress: {:?}", why),
             Ok(_) => {},
         };
 
+        #[allow(clippy::match_single_binding)]
         match encoder.finish() {
             (_, res) => res.unwrap(),
         };
     }
 
+    #[allow(clippy::needless_borrow)]
     let encoded = (&*compressed).to_base64(STANDARD);
     print!("{}", encoded);
 }
@@ -0,0 +1,518 @@
+/*!
+ * An NSS module serving passwd, group and shadow entries from CDB files, as
+ * a replacement for TinyCDB's `nss_cdb` C module.
+ *
+ * The databases are read from `/etc/passwd.cdb`, `/etc/group.cdb` and
+ * `/etc/shadow.cdb`, or from the directory given in the `NSSCDB_DIR`
+ * environment variable at build time.  They use the same layout as the C
+ * module; see `tinycdb::nss`.  To use it, install the library as
+ * `libnss_cdb.so.2` and add `cdb` to the relevant lines of
+ * `/etc/nsswitch.conf`.
+ */
+#![warn(missing_docs)]
+
+extern crate libc;
+extern crate tinycdb;
+
+use std::ffi::CStr;
+use std::mem;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+use libc::{c_char, c_int, c_long, c_ulong, gid_t, group, passwd, size_t, spwd, uid_t};
+use libc::{EIO, ENOENT, ERANGE};
+
+use tinycdb::nss::{Group, NssDb, NssEntry, Passwd, Shadow};
+use tinycdb::{CdbCursor, CdbError, CdbErrorKind, CdbResult};
+
+const NSSCDB_DIR: &str = match option_env!("NSSCDB_DIR") {
+    Some(dir) => dir,
+    None      => "/etc",
+};
+
+/// The values of glibc's `enum nss_status`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NssStatus {
+    /// The buffer was too small, and the call should be retried with a
+    /// larger one.
+    TryAgain = -2,
+    /// The database could not be read.
+    Unavail = -1,
+    /// There is no such entry.
+    NotFound = 0,
+    /// The entry was found.
+    Success = 1,
+}
+
+// The caller-supplied buffer that strings and arrays are stored in.
+struct Buffer {
+    ptr: *mut c_char,
+    len: usize,
+    used: usize,
+}
+
+impl Buffer {
+    unsafe fn new(ptr: *mut c_char, len: size_t) -> Buffer {
+        Buffer { ptr, len, used: 0 }
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> Option<*mut c_char> {
+        let addr = self.ptr as usize + self.used;
+        let start = self.used + (align - addr % align) % align;
+        if start.checked_add(size)? > self.len {
+            return None;
+        }
+        self.used = start + size;
+        Some(unsafe { self.ptr.add(start) })
+    }
+
+    fn string(&mut self, s: &str) -> Option<*mut c_char> {
+        let ret = self.alloc(s.len() + 1, 1)?;
+        unsafe {
+            ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, ret, s.len());
+            *ret.add(s.len()) = 0;
+        }
+        Some(ret)
+    }
+
+    fn strings(&mut self, ss: &[String]) -> Option<*mut *mut c_char> {
+        let size = mem::size_of::<*mut c_char>();
+        let ret = self.alloc((ss.len() + 1) * size, size)? as *mut *mut c_char;
+        for (i, s) in ss.iter().enumerate() {
+            let s = self.string(s)?;
+            unsafe { *ret.add(i) = s };
+        }
+        unsafe { *ret.add(ss.len()) = ptr::null_mut() };
+        Some(ret)
+    }
+}
+
+// Copies an entry into the C struct that glibc passes in.  Returns `None`
+// if the buffer is too small.
+trait Fill: NssEntry {
+    type Out;
+
+    fn fill(&self, out: &mut Self::Out, buf: &mut Buffer) -> Option<()>;
+}
+
+impl Fill for Passwd {
+    type Out = passwd;
+
+    fn fill(&self, out: &mut passwd, buf: &mut Buffer) -> Option<()> {
+        out.pw_name = buf.string(&self.name)?;
+        out.pw_passwd = buf.string(&self.passwd)?;
+        out.pw_uid = self.uid as uid_t;
+        out.pw_gid = self.gid as gid_t;
+        out.pw_gecos = buf.string(&self.gecos)?;
+        out.pw_dir = buf.string(&self.dir)?;
+        out.pw_shell = buf.string(&self.shell)?;
+        Some(())
+    }
+}
+
+impl Fill for Group {
+    type Out = group;
+
+    fn fill(&self, out: &mut group, buf: &mut Buffer) -> Option<()> {
+        out.gr_name = buf.string(&self.name)?;
+        out.gr_passwd = buf.string(&self.passwd)?;
+        out.gr_gid = self.gid as gid_t;
+        out.gr_mem = buf.strings(&self.members)?;
+        Some(())
+    }
+}
+
+impl Fill for Shadow {
+    type Out = spwd;
+
+    fn fill(&self, out: &mut spwd, buf: &mut Buffer) -> Option<()> {
+        let days = |v: Option<i64>| v.map_or(-1, |v| v as c_long);
+        out.sp_namp = buf.string(&self.name)?;
+        out.sp_pwdp = buf.string(&self.passwd)?;
+        out.sp_lstchg = days(self.last_change);
+        out.sp_min = days(self.min);
+        out.sp_max = days(self.max);
+        out.sp_warn = days(self.warn);
+        out.sp_inact = days(self.inactive);
+        out.sp_expire = days(self.expire);
+        out.sp_flag = self.flag.map_or(!0, |v| v as c_ulong);
+        Some(())
+    }
+}
+
+// The state of one of the databases, mirroring `struct nss_cdb`.
+struct State<T> {
+    dir: &'static str,
+    name: &'static str,
+    db: Option<NssDb<T>>,
+    keep_open: bool,
+    cursor: Option<CdbCursor>,
+}
+
+impl<T: Fill> State<T> {
+    const fn new(dir: &'static str, name: &'static str) -> State<T> {
+        State {
+            dir,
+            name,
+            db: None,
+            keep_open: false,
+            cursor: None,
+        }
+    }
+
+    fn open(&mut self) -> Result<&NssDb<T>, c_int> {
+        if self.db.is_none() {
+            let path = PathBuf::from(format!("{}/{}.cdb", self.dir, self.name));
+            self.db = Some(NssDb::open(&path).map_err(|e| error_code(&e))?);
+        }
+        Ok(self.db.as_ref().unwrap())
+    }
+
+    fn close(&mut self) {
+        self.db = None;
+        self.keep_open = false;
+        self.cursor = None;
+    }
+
+    fn setent(&mut self, stay_open: bool) -> NssStatus {
+        match self.open() {
+            Ok(_) => {
+                self.keep_open |= stay_open;
+                self.cursor = None;
+                NssStatus::Success
+            },
+            Err(_) => NssStatus::Unavail,
+        }
+    }
+
+    // Looks up a single entry, closing the database afterwards unless it was
+    // opened with `setent(1)` or is being enumerated.
+    unsafe fn lookup<F>(&mut self, find: F, out: *mut T::Out, buf: *mut c_char, buflen: size_t,
+                        errnop: *mut c_int) -> NssStatus
+        where F: FnOnce(&NssDb<T>) -> CdbResult<Option<T>>
+    {
+        let ret = match self.open().map(find) {
+            Ok(Ok(Some(entry))) => fill(&entry, out, buf, buflen, errnop),
+            Ok(Ok(None))        => not_found(errnop),
+            Ok(Err(e))          => {
+                *errnop = error_code(&e);
+                NssStatus::Unavail
+            },
+            Err(e) => {
+                *errnop = e;
+                NssStatus::Unavail
+            },
+        };
+        if !self.keep_open {
+            self.close();
+        }
+        ret
+    }
+
+    unsafe fn getent(&mut self, out: *mut T::Out, buf: *mut c_char, buflen: size_t,
+                     errnop: *mut c_int) -> NssStatus {
+        self.keep_open = true;
+        let cursor = self.cursor;
+        let db = match self.open() {
+            Ok(db) => db,
+            Err(e) => {
+                *errnop = e;
+                return NssStatus::Unavail;
+            },
+        };
+
+        // Resuming from the cursor takes constant time, so there's no need to
+        // keep the iterator between calls.
+        let mut iter = match cursor {
+            Some(cursor) => match db.iter_from(cursor) {
+                Ok(iter) => iter,
+                Err(_)   => return not_found(errnop),
+            },
+            None => db.iter(),
+        };
+        let ret = match iter.next() {
+            Some(entry) => fill(&entry, out, buf, buflen, errnop),
+            None        => not_found(errnop),
+        };
+
+        // Stay on the same entry if the buffer was too small, so that it is
+        // returned again by the retry.
+        if ret != NssStatus::TryAgain {
+            self.cursor = Some(iter.cursor());
+        }
+        ret
+    }
+}
+
+// The errno value to report for an error.
+fn error_code(e: &CdbError) -> c_int {
+    match *e.kind() {
+        CdbErrorKind::IoError(ref e) => e.raw_os_error().unwrap_or(EIO),
+        _                            => EIO,
+    }
+}
+
+unsafe fn not_found(errnop: *mut c_int) -> NssStatus {
+    *errnop = ENOENT;
+    NssStatus::NotFound
+}
+
+unsafe fn fill<T: Fill>(entry: &T, out: *mut T::Out, buf: *mut c_char, buflen: size_t,
+                        errnop: *mut c_int) -> NssStatus {
+    let mut buf = Buffer::new(buf, buflen);
+    match entry.fill(&mut *out, &mut buf) {
+        Some(()) => NssStatus::Success,
+        None     => {
+            *errnop = ERANGE;
+            NssStatus::TryAgain
+        },
+    }
+}
+
+// Looks up an entry by name.  Names that aren't valid UTF-8 can't be in the
+// database.
+unsafe fn by_name<T: NssEntry>(db: &NssDb<T>, name: *const c_char) -> CdbResult<Option<T>> {
+    match CStr::from_ptr(name).to_str() {
+        Ok(name) => db.by_name(name),
+        Err(_)   => Ok(None),
+    }
+}
+
+fn lock<T>(state: &Mutex<T>) -> MutexGuard<'_, T> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+static PASSWD: Mutex<State<Passwd>> = Mutex::new(State::new(NSSCDB_DIR, "passwd"));
+static GROUP: Mutex<State<Group>> = Mutex::new(State::new(NSSCDB_DIR, "group"));
+static SHADOW: Mutex<State<Shadow>> = Mutex::new(State::new(NSSCDB_DIR, "shadow"));
+
+/// Starts enumerating the passwd database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_setpwent(stayopen: c_int) -> NssStatus {
+    lock(&PASSWD).setent(stayopen != 0)
+}
+
+/// Stops enumerating the passwd database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_endpwent() -> NssStatus {
+    lock(&PASSWD).close();
+    NssStatus::Success
+}
+
+/// Returns the next entry of the passwd database.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getpwent_r(result: *mut passwd, buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&PASSWD).getent(result, buf, buflen, errnop)
+}
+
+/// Looks up a passwd entry by name.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getpwnam_r(name: *const c_char, result: *mut passwd,
+                                             buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&PASSWD).lookup(|db| by_name(db, name), result, buf, buflen, errnop)
+}
+
+/// Looks up a passwd entry by uid.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getpwuid_r(uid: uid_t, result: *mut passwd, buf: *mut c_char,
+                                             buflen: size_t, errnop: *mut c_int) -> NssStatus {
+    lock(&PASSWD).lookup(|db| db.by_id(uid), result, buf, buflen, errnop)
+}
+
+/// Starts enumerating the group database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_setgrent(stayopen: c_int) -> NssStatus {
+    lock(&GROUP).setent(stayopen != 0)
+}
+
+/// Stops enumerating the group database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_endgrent() -> NssStatus {
+    lock(&GROUP).close();
+    NssStatus::Success
+}
+
+/// Returns the next entry of the group database.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getgrent_r(result: *mut group, buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&GROUP).getent(result, buf, buflen, errnop)
+}
+
+/// Looks up a group entry by name.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getgrnam_r(name: *const c_char, result: *mut group,
+                                             buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&GROUP).lookup(|db| by_name(db, name), result, buf, buflen, errnop)
+}
+
+/// Looks up a group entry by gid.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getgrgid_r(gid: gid_t, result: *mut group, buf: *mut c_char,
+                                             buflen: size_t, errnop: *mut c_int) -> NssStatus {
+    lock(&GROUP).lookup(|db| db.by_id(gid), result, buf, buflen, errnop)
+}
+
+/// Starts enumerating the shadow database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_setspent(stayopen: c_int) -> NssStatus {
+    lock(&SHADOW).setent(stayopen != 0)
+}
+
+/// Stops enumerating the shadow database.
+#[no_mangle]
+pub extern "C" fn _nss_cdb_endspent() -> NssStatus {
+    lock(&SHADOW).close();
+    NssStatus::Success
+}
+
+/// Returns the next entry of the shadow database.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getspent_r(result: *mut spwd, buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&SHADOW).getent(result, buf, buflen, errnop)
+}
+
+/// Looks up a shadow entry by name.
+///
+/// # Safety
+///
+/// The pointers must be valid, as for any NSS module.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_cdb_getspnam_r(name: *const c_char, result: *mut spwd,
+                                             buf: *mut c_char, buflen: size_t,
+                                             errnop: *mut c_int) -> NssStatus {
+    lock(&SHADOW).lookup(|db| by_name(db, name), result, buf, buflen, errnop)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use libc::{c_char, c_int, group};
+    use libc::{ENOENT, ERANGE};
+
+    use tinycdb::nss::{Group, NssDb};
+
+    use super::{NssStatus, State};
+
+    // Helper to remove test files after a test is finished, even if the test
+    // panic!()s
+    struct RemovingPath {
+        underlying: PathBuf,
+    }
+
+    impl Drop for RemovingPath {
+        fn drop(&mut self) {
+            if let Err(why) = fs::remove_file(&self.underlying) {
+                println!("Couldn't remove temp file: {:?}", why);
+            }
+        }
+    }
+
+    #[test]
+    fn test_group() {
+        let path = Path::new("nss_test_group.cdb");
+        let _ = fs::remove_file(path);
+        let _rem = RemovingPath { underlying: path.to_owned() };
+        NssDb::<Group>::create(path, "wheel:x:10:root,alice\nusers:x:100:\n").unwrap();
+
+        let mut state: State<Group> = State::new(".", "nss_test_group");
+        let mut gr: group = unsafe { std::mem::zeroed() };
+        let mut buf = [0 as c_char; 256];
+        let mut errno: c_int = 0;
+
+        unsafe {
+            let res = state.lookup(|db| db.by_id(10), &mut gr, buf.as_mut_ptr(), buf.len(),
+                                   &mut errno);
+            assert_eq!(res, NssStatus::Success);
+            assert_eq!(CStr::from_ptr(gr.gr_name).to_bytes(), b"wheel");
+            assert_eq!(CStr::from_ptr(*gr.gr_mem.add(1)).to_bytes(), b"alice");
+            assert!((*gr.gr_mem.add(2)).is_null());
+            assert!(state.db.is_none());
+
+            let res = state.lookup(|db| db.by_name("nope"), &mut gr, buf.as_mut_ptr(),
+                                   buf.len(), &mut errno);
+            assert_eq!((res, errno), (NssStatus::NotFound, ENOENT));
+
+            // A buffer that's too small leaves enumeration where it was.
+            assert_eq!(state.setent(false), NssStatus::Success);
+            let res = state.getent(&mut gr, buf.as_mut_ptr(), 4, &mut errno);
+            assert_eq!((res, errno), (NssStatus::TryAgain, ERANGE));
+
+            let mut names = Vec::new();
+            while state.getent(&mut gr, buf.as_mut_ptr(), buf.len(), &mut errno) == NssStatus::Success {
+                names.push(CStr::from_ptr(gr.gr_name).to_bytes().to_vec());
+                assert!(names.len() <= 2);
+            }
+            assert_eq!(names, vec![b"wheel".to_vec(), b"users".to_vec()]);
+            state.close();
+
+            // Failing to open the database reports why.
+            let mut missing: State<Group> = State::new(".", "nss_test_missing");
+            let res = missing.lookup(|db| db.by_id(10), &mut gr, buf.as_mut_ptr(), buf.len(),
+                                     &mut errno);
+            assert_eq!((res, errno), (NssStatus::Unavail, ENOENT));
+        }
+    }
+
+    #[test]
+    fn test_getent_many() {
+        let path = Path::new("nss_test_many.cdb");
+        let _ = fs::remove_file(path);
+        let _rem = RemovingPath { underlying: path.to_owned() };
+        let text: String = (0..20000).map(|i| format!("group{}:x:{}:\n", i, 1000 + i)).collect();
+        NssDb::<Group>::create(path, &text).unwrap();
+
+        // Each step resumes from where the last one stopped, which has to be
+        // cheap for enumerating a large database to finish in reasonable
+        // time.
+        let mut state: State<Group> = State::new(".", "nss_test_many");
+        let mut gr: group = unsafe { std::mem::zeroed() };
+        let mut buf = [0 as c_char; 256];
+        let mut errno: c_int = 0;
+        let mut count = 0;
+        unsafe {
+            while state.getent(&mut gr, buf.as_mut_ptr(), buf.len(), &mut errno) == NssStatus::Success {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 20000);
+        assert_eq!(errno, ENOENT);
+    }
+}
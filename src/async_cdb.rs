@@ -0,0 +1,365 @@
+/*!
+ * Creating and opening databases from async code, available with the
+ * `tokio` feature.
+ *
+ * Lookups only read from the memory mapping, so they can be done directly
+ * from async code.  Creating and opening a database, on the other hand, do
+ * blocking I/O, so these are moved onto tokio's blocking thread pool.
+ */
+
+use std::fs;
+use std::future::poll_fn;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use super::{Cdb, CdbCreator, CdbError, CdbErrorKind, CdbResult, OpenOptions};
+
+// How many records can be waiting to be written before `add` blocks.
+const QUEUE_SIZE: usize = 1024;
+
+// Runs a blocking function on the blocking thread pool.
+async fn blocking<T, F>(f: F) -> CdbResult<T>
+    where F: FnOnce() -> CdbResult<T> + Send + 'static,
+          T: Send + 'static
+{
+    match task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) => Err(CdbError::new(
+            "Blocking task failed",
+            CdbErrorKind::IoError(std::io::Error::other(e)),
+        )),
+    }
+}
+
+// What an `AsyncCdbCreator` tells its writer.
+enum Message {
+    Record(Vec<u8>, Vec<u8>),
+    Finish,
+}
+
+// Writes the records it is sent, and completes the database once told to.
+// Fails if the creator goes away without saying so.
+fn write(creator: &mut CdbCreator, rx: &mut mpsc::Receiver<Message>) -> CdbResult<()> {
+    while let Some(msg) = rx.blocking_recv() {
+        match msg {
+            Message::Record(key, val) => creator.add(&key, &val)?,
+            Message::Finish           => return creator.finalize(),
+        }
+    }
+    Err(CdbError::new(
+        "Creator was dropped before finishing",
+        CdbErrorKind::IoError(std::io::Error::from(std::io::ErrorKind::Interrupted)),
+    ))
+}
+
+/// An `AsyncCdbCreator` builds a new database from async code.  Records are
+/// handed to a task on the blocking thread pool, which does the writing.
+///
+/// The database is only completed by `finish()`.  If the creator is dropped
+/// before that, for instance because the future using it was cancelled, or
+/// if writing fails, the partly written file is removed.
+pub struct AsyncCdbCreator {
+    path: PathBuf,
+    options: OpenOptions,
+    records: Option<mpsc::Sender<Message>>,
+    writer: Option<JoinHandle<CdbResult<()>>>,
+}
+
+impl AsyncCdbCreator {
+    /**
+     * `new(path)` starts creating a new database at the given path.  The
+     * file must not exist yet.
+     */
+    pub async fn new(path: &Path) -> CdbResult<AsyncCdbCreator> {
+        AsyncCdbCreator::new_with(path, &OpenOptions::new()).await
+    }
+
+    /**
+     * `new_with(path, options)` starts creating a new database at the given
+     * path, like `new`.  The options are used to open it once it's finished.
+     */
+    pub async fn new_with(path: &Path, options: &OpenOptions) -> CdbResult<AsyncCdbCreator> {
+        let (tx, mut rx) = mpsc::channel::<Message>(QUEUE_SIZE);
+        let (started_tx, started_rx) = oneshot::channel();
+
+        // The creator is made on the writer's thread, and stays there.
+        let owned = path.to_path_buf();
+        let writer = task::spawn_blocking(move || {
+            let mut creator = match CdbCreator::new(&owned, 0o644) {
+                Ok(creator) => {
+                    let _ = started_tx.send(Ok(()));
+                    creator
+                },
+                Err(e) => {
+                    let _ = started_tx.send(Err(e));
+                    return Ok(());
+                },
+            };
+
+            let res = write(&mut creator, &mut rx);
+            if res.is_err() {
+                // Don't leave a partial database behind.
+                drop(creator);
+                let _ = fs::remove_file(&owned);
+            }
+            res
+        });
+
+        match started_rx.await {
+            Ok(res) => res?,
+            Err(_)  => return Err(join(writer).await.err().unwrap_or_else(stopped)),
+        }
+
+        Ok(AsyncCdbCreator {
+            path: path.to_path_buf(),
+            options: options.clone(),
+            records: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    /**
+     * `add(key, val)` adds the given key/value pair to the database.  This
+     * waits only if the writer has fallen behind.  If writing an earlier
+     * record failed, the error is returned here.
+     */
+    pub async fn add(&mut self, key: Vec<u8>, val: Vec<u8>) -> CdbResult<()> {
+        let sent = match self.records {
+            Some(ref tx) => tx.send(Message::Record(key, val)).await.is_ok(),
+            None         => false,
+        };
+        if sent {
+            return Ok(());
+        }
+
+        // The writer only stops early when it fails, so find out why.
+        self.records = None;
+        match self.writer.take() {
+            Some(writer) => match join(writer).await {
+                Ok(()) => Err(stopped()),
+                Err(e) => Err(e),
+            },
+            None => Err(stopped()),
+        }
+    }
+
+    /**
+     * `add_stream(records)` adds every key/value pair from the given stream
+     * to the database, stopping at the first error.
+     */
+    pub async fn add_stream<S>(&mut self, mut records: S) -> CdbResult<()>
+        where S: Stream<Item = (Vec<u8>, Vec<u8>)> + Unpin
+    {
+        while let Some((key, val)) = poll_fn(|cx| Pin::new(&mut records).poll_next(cx)).await {
+            self.add(key, val).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * `finish()` waits for all records to be written, completes the
+     * database, and opens it.
+     */
+    pub async fn finish(mut self) -> CdbResult<Box<Cdb>> {
+        // If the writer has already stopped, joining it tells us why.
+        if let Some(tx) = self.records.take() {
+            let _ = tx.send(Message::Finish).await;
+        }
+        match self.writer.take() {
+            Some(writer) => join(writer).await?,
+            None         => return Err(stopped()),
+        }
+
+        let path = self.path;
+        let options = self.options;
+        blocking(move || Cdb::open_with(&path, &options)).await
+    }
+}
+
+fn stopped() -> CdbError {
+    CdbError::new(
+        "Creator has stopped",
+        CdbErrorKind::IoError(std::io::Error::from(std::io::ErrorKind::BrokenPipe)),
+    )
+}
+
+async fn join(writer: JoinHandle<CdbResult<()>>) -> CdbResult<()> {
+    match writer.await {
+        Ok(res) => res,
+        Err(e) => Err(CdbError::new(
+            "Creator task failed",
+            CdbErrorKind::IoError(std::io::Error::other(e)),
+        )),
+    }
+}
+
+impl Cdb {
+    /**
+     * `open_async(path)` opens the database at the given path like `open`,
+     * but on the blocking thread pool.
+     */
+    pub async fn open_async(path: &Path) -> CdbResult<Box<Cdb>> {
+        Cdb::open_with_async(path, &OpenOptions::new()).await
+    }
+
+    /**
+     * `open_with_async(path, options)` opens the database at the given path
+     * like `open_with`, but on the blocking thread pool.
+     */
+    pub async fn open_with_async(path: &Path, options: &OpenOptions) -> CdbResult<Box<Cdb>> {
+        let path = path.to_path_buf();
+        let options = options.clone();
+        blocking(move || Cdb::open_with(&path, &options)).await
+    }
+}
+
+/// An `AsyncCdb` holds the current version of a database that is replaced
+/// on disk from time to time.  Lookups go through the `Cdb` returned by
+/// `get()`, which stays usable even after a newer version is loaded with
+/// `reload()`.
+pub struct AsyncCdb {
+    path: PathBuf,
+    options: OpenOptions,
+    current: RwLock<Arc<Cdb>>,
+}
+
+impl AsyncCdb {
+    /**
+     * `open(path)` opens the database at the given path on the blocking
+     * thread pool.
+     */
+    pub async fn open(path: &Path) -> CdbResult<AsyncCdb> {
+        AsyncCdb::open_with(path, &OpenOptions::new()).await
+    }
+
+    /**
+     * `open_with(path, options)` opens the database at the given path on the
+     * blocking thread pool, with the given options.  The same options are
+     * used by every `reload()`.
+     */
+    pub async fn open_with(path: &Path, options: &OpenOptions) -> CdbResult<AsyncCdb> {
+        let db = Cdb::open_with_async(path, options).await?;
+        Ok(AsyncCdb {
+            path: path.to_path_buf(),
+            options: options.clone(),
+            current: RwLock::new(Arc::from(db)),
+        })
+    }
+
+    /**
+     * `get()` returns the most recently loaded version of the database.
+     */
+    pub fn get(&self) -> Arc<Cdb> {
+        match self.current.read() {
+            Ok(db) => db.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /**
+     * `reload()` opens the database again, picking up the file that is now
+     * at its path, and makes it the one returned by `get()`.  If opening
+     * fails, the previous version stays in place.
+     */
+    pub async fn reload(&self) -> CdbResult<()> {
+        let db: Arc<Cdb> = Arc::from(Cdb::open_with_async(&self.path, &self.options).await?);
+        match self.current.write() {
+            Ok(mut current) => *current = db,
+            Err(poisoned) => *poisoned.into_inner() = db,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_core::Stream;
+    use tokio::runtime;
+
+    use super::super::Cdb;
+    use super::super::tests::RemovingPath;
+    use super::{AsyncCdb, AsyncCdbCreator};
+
+    struct Records(std::vec::IntoIter<(Vec<u8>, Vec<u8>)>);
+
+    impl Stream for Records {
+        type Item = (Vec<u8>, Vec<u8>);
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    #[test]
+    fn test_async() {
+        let path = Path::new("async.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut creator = AsyncCdbCreator::new(path).await.unwrap();
+            creator.add(b"one".to_vec(), b"1".to_vec()).await.unwrap();
+
+            let records: Vec<(Vec<u8>, Vec<u8>)> = (0..5000)
+                .map(|i| (format!("key{}", i).into_bytes(), format!("val{}", i).into_bytes()))
+                .collect();
+            creator.add_stream(Records(records.into_iter())).await.unwrap();
+
+            let c = creator.finish().await.unwrap();
+            assert_eq!(c.len(), 5001);
+            assert_eq!(c.find_all(b"key4999").next(), Some(&b"val4999"[..]));
+
+            // Creating over an existing file fails up front.
+            assert!(AsyncCdbCreator::new(path).await.is_err());
+
+            let db = AsyncCdb::open(path).await.unwrap();
+            let old = db.get();
+            assert!(old.find_all(b"two").next().is_none());
+
+            // Replace the file, and pick up the new version.
+            std::fs::remove_file(path).unwrap();
+            Cdb::new(path, |creator| {
+                creator.add(b"two", b"2").unwrap();
+            }).unwrap();
+            db.reload().await.unwrap();
+
+            assert_eq!(db.get().find_all(b"two").next(), Some(&b"2"[..]));
+            assert_eq!(old.find_all(b"one").next(), Some(&b"1"[..]));
+
+            std::fs::remove_file(path).unwrap();
+            assert!(db.reload().await.is_err());
+            assert_eq!(db.get().len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_async_dropped() {
+        let path = Path::new("async_dropped.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let rt = runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut creator = AsyncCdbCreator::new(path).await.unwrap();
+            for i in 0..100 {
+                creator.add(format!("key{}", i).into_bytes(), b"val".to_vec()).await.unwrap();
+            }
+
+            // Dropped without `finish()`, as when returning early with `?`.
+            drop(creator);
+        });
+
+        // Dropping the runtime waits for the writer to stop.
+        drop(rt);
+        assert!(!path.exists());
+        assert!(Cdb::open(path).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+/*!
+ * Checksums for catching databases that were corrupted on disk or in
+ * transit.
+ *
+ * The CDB format has no checksums of its own.  `Checksums` holds a CRC32C of
+ * the whole file, which `Cdb::verify_checksum` compares against, and one of
+ * every record, which lookups check in the checked mode enabled with
+ * `OpenOptions::checked`.  Checking a record only reads that record, so this
+ * is much cheaper than verifying the whole file before using it.
+ *
+ * Checksums can be computed by `CdbCreator` when asked to with
+ * `set_checksums`, and are then stored in a separate file next to the
+ * database, as a `KeyIndex` is.  Like a `KeyIndex`, they remember a
+ * fingerprint of the database they were computed for, so that checksums
+ * of another database are refused up front rather than failing every
+ * checked lookup.
+ */
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use libc::{self, c_uint};
+
+use super::{format, Cdb, CdbError, CdbErrorKind, CdbResult};
+
+const MAGIC: &[u8; 8] = b"TCDBSUM1";
+
+// Errors about malformed files are reported the same way TinyCDB does.
+fn protocol_error(msg: &'static str) -> CdbError {
+    CdbError::new(msg, CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)))
+}
+
+fn mismatch_error(msg: &'static str) -> CdbError {
+    CdbError::new(msg, CdbErrorKind::ChecksumMismatch)
+}
+
+/// Checksums of a database, both of the file as a whole and of every record
+/// in it.  Available with the `checksum` feature.
+#[derive(Clone, Debug)]
+pub struct Checksums {
+    fingerprint: u64,
+    size: u64,
+    digest: u32,
+
+    // The offset and checksum of every record the hash tables point at,
+    // sorted by offset.
+    records: Vec<(c_uint, u32)>,
+}
+
+impl Checksums {
+    /**
+     * `build(db)` computes the checksums of the given database.  This reads
+     * the whole file.
+     */
+    pub fn build(db: &Cdb) -> CdbResult<Checksums> {
+        let mem = db.mapping()?;
+
+        // The metadata record is checksummed like any other.
+        let mut records: Vec<(c_uint, u32)> = db.table_offsets()
+            .filter_map(|offset| record_bytes(db, offset).map(|rec| (offset, crc32c::crc32c(rec))))
+            .collect();
+        records.sort_unstable();
+
+        Ok(Checksums {
+            fingerprint: db.fingerprint()?,
+            size: mem.len() as u64,
+            digest: crc32c::crc32c(mem),
+            records,
+        })
+    }
+
+    /**
+     * `sidecar_path(db)` returns the conventional location of the checksums
+     * for the database at `db`, which is the same path with `.checksums`
+     * appended.
+     */
+    pub fn sidecar_path(db: &Path) -> PathBuf {
+        let mut path = db.as_os_str().to_owned();
+        path.push(".checksums");
+        PathBuf::from(path)
+    }
+
+    /**
+     * `open(path)` reads checksums from the file at the given path.
+     */
+    pub fn open(path: &Path) -> CdbResult<Checksums> {
+        match File::open(path) {
+            Ok(mut f) => Checksums::read_from(&mut f),
+            Err(e) => Err(CdbError::new("Error opening checksums", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * `read_from(input)` reads checksums in the format written by
+     * `write_to`.
+     */
+    pub fn read_from<R: Read>(input: &mut R) -> CdbResult<Checksums> {
+        let mut buf = Vec::new();
+        if let Err(e) = input.read_to_end(&mut buf) {
+            return Err(CdbError::new("Error reading checksums", CdbErrorKind::IoError(e)));
+        }
+
+        if buf.len() < 28 || &buf[..8] != MAGIC || (buf.len() - 28) % 8 != 0 {
+            return Err(protocol_error("Invalid checksums format"));
+        }
+
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&buf[8..16]);
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&buf[16..24]);
+        let records: Vec<(c_uint, u32)> = buf[28..].chunks(8)
+            .map(|b| (format::unpack(b), format::unpack(&b[4..])))
+            .collect();
+        if records.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(protocol_error("Invalid checksums format"));
+        }
+
+        Ok(Checksums {
+            fingerprint: u64::from_le_bytes(fingerprint),
+            size: u64::from_le_bytes(size),
+            digest: format::unpack(&buf[24..28]),
+            records,
+        })
+    }
+
+    /**
+     * `write_to(out)` writes the checksums out, so that they can later be
+     * read back with `read_from`.
+     */
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.fingerprint.to_le_bytes())?;
+        out.write_all(&self.size.to_le_bytes())?;
+        out.write_all(&format::pack(self.digest))?;
+        for &(offset, crc) in &self.records {
+            out.write_all(&format::pack(offset))?;
+            out.write_all(&format::pack(crc))?;
+        }
+        Ok(())
+    }
+
+    // Writes the checksums out to the given path.
+    pub(crate) fn write_file(&self, path: &Path) -> CdbResult<()> {
+        match File::create(path).and_then(|mut f| self.write_to(&mut f)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CdbError::new("Error writing checksums", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * Returns the CRC32C of the whole file.
+     */
+    pub fn digest(&self) -> u32 {
+        self.digest
+    }
+
+    /**
+     * Returns the number of records with a checksum.
+     */
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /**
+     * Returns whether there are no records with a checksum.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+// Returns the whole record at the given offset, header included, if it's
+// within the file.
+fn record_bytes(db: &Cdb, offset: c_uint) -> Option<&[u8]> {
+    let (key, val) = db.record(offset)?;
+    let len = format::RECORD_HEADER_SIZE + key.len() + val.len();
+    db.get_checked(offset, len as c_uint)
+}
+
+impl Cdb {
+    /**
+     * `set_checksums(checksums)` loads the checksums computed for this
+     * database, for `verify_checksum()` and checked lookups.  Fails if they
+     * were computed for a different database.  Whether the contents actually
+     * match is only found out by checking.
+     */
+    pub fn set_checksums(&mut self, checksums: Checksums) -> CdbResult<()> {
+        if checksums.fingerprint != self.fingerprint()? {
+            return Err(protocol_error("Checksums do not match database"));
+        }
+
+        self.checksums = Some(checksums);
+        Ok(())
+    }
+
+    /**
+     * `verify_checksum()` checks the whole file against the checksum loaded
+     * with `set_checksums`, or by `OpenOptions::checksums`.  This reads the
+     * whole file.  Fails with `CdbErrorKind::ChecksumMismatch` if the file
+     * doesn't match.
+     */
+    pub fn verify_checksum(&self) -> CdbResult<()> {
+        let checksums = match self.checksums {
+            Some(ref checksums) => checksums,
+            None                => return Err(CdbError::new(
+                "No checksums loaded",
+                CdbErrorKind::IoError(io::Error::from(io::ErrorKind::InvalidInput)),
+            )),
+        };
+
+        let mem = self.mapping()?;
+        if mem.len() as u64 != checksums.size {
+            return Err(mismatch_error("Database size does not match its checksums"));
+        }
+        if crc32c::crc32c(mem) != checksums.digest {
+            return Err(mismatch_error("Database does not match its checksum"));
+        }
+        Ok(())
+    }
+
+    // Whether the record with a `klen`-byte key and its value at `pos`
+    // matches its checksum.  Always true when not in checked mode.
+    pub(crate) fn value_ok(&self, klen: c_uint, pos: c_uint) -> bool {
+        let checksums = match self.checksums {
+            Some(ref checksums) if self.checked => checksums,
+            _ => return true,
+        };
+
+        // Records without a checksum fail, since the hash tables themselves
+        // may be what was corrupted.
+        let offset = pos - klen - format::RECORD_HEADER_SIZE as c_uint;
+        match checksums.records.binary_search_by_key(&offset, |&(o, _)| o) {
+            Ok(i) => record_bytes(self, offset).is_some_and(|rec| crc32c::crc32c(rec) == checksums.records[i].1),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions as FileOptions;
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use super::super::tests::RemovingPath;
+    use super::super::metadata::METADATA_KEY;
+    use super::super::{Cdb, CdbErrorKind, CdbResult, OpenOptions};
+    use super::Checksums;
+
+    fn is_mismatch<T>(res: CdbResult<T>) -> bool {
+        match res {
+            Err(ref e) => matches!(*e.kind(), CdbErrorKind::ChecksumMismatch),
+            Ok(_)      => false,
+        }
+    }
+
+    #[test]
+    fn test_checksums() {
+        let path = Path::new("checksums.cdb");
+        let _rem = RemovingPath::new(path);
+        let sums_path = Checksums::sidecar_path(path);
+        let _rem_sums = RemovingPath::new(&sums_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_checksums(&sums_path);
+            creator.set_metadata("schema", b"1").unwrap();
+            creator.add(b"one", b"Hello").unwrap();
+            creator.add(b"two", b"Goodbye").unwrap();
+            creator.add(b"one", b"Again").unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+        c.verify_checksum().unwrap();
+
+        let sums = Checksums::open(&sums_path).unwrap();
+        // The records, the metadata and the id of the database.
+        assert_eq!(sums.len(), 5);
+
+        // Checked lookups of intact records work as usual, and so does the
+        // metadata.
+        let mut c = Cdb::open_with(path, OpenOptions::new().checksums(&sums_path).checked(true)).unwrap();
+        assert_eq!(c.find(b"two").unwrap(), b"Goodbye");
+        assert_eq!(c.find_all(b"one").collect::<Vec<_>>(), vec![&b"Hello"[..], &b"Again"[..]]);
+        assert_eq!(c.metadata().unwrap()["schema"], b"1");
+
+        // Corrupt the value of the first record.
+        let f = FileOptions::new().write(true).open(path).unwrap();
+        f.write_at(b"J", 2048 + 8 + 3).unwrap();
+
+        assert!(is_mismatch(c.verify_checksum()));
+        assert_eq!(c.find(b"one").unwrap(), b"Again");
+        assert!(c.exists(b"one"));
+        assert_eq!(c.find_all(b"one").collect::<Vec<_>>(), vec![&b"Again"[..]]);
+        assert_eq!(c.find(b"two").unwrap(), b"Goodbye");
+
+        // So do the lookups that return a `CdbResult`.
+        let mut buf = Vec::new();
+        assert!(c.find_into(b"one", &mut buf).unwrap());
+        assert_eq!(buf, b"Again");
+        let mut small = [0u8; 3];
+        assert_eq!(c.get_into(b"one", &mut small).unwrap(), Some(5));
+        assert_eq!(&small, b"Aga");
+        buf.clear();
+        c.find_reader(b"one").unwrap().unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"Again");
+        assert_eq!(c.find_many(&[b"one", b"two"]).unwrap(), vec![Some(&b"Again"[..]), Some(&b"Goodbye"[..])]);
+
+        // Corrupting the metadata hides it from checked lookups, but it is
+        // still not counted as a record.
+        let meta = c.internal_offsets()[0];
+        f.write_at(b"X", (meta as usize + 8 + METADATA_KEY.len()) as u64).unwrap();
+        assert!(c.metadata().unwrap().is_empty());
+        assert_eq!(c.len(), 3);
+        assert_eq!(c.iter().len(), 3);
+        assert_eq!(c.iter().count(), 3);
+
+        // A key whose only record is corrupted isn't found.
+        f.write_at(b"X", 2048 + 16 + 8 + 3).unwrap();
+        assert!(c.find(b"two").is_none());
+        assert!(!c.exists(b"two"));
+        assert!(!c.find_into(b"two", &mut buf).unwrap());
+        assert_eq!(c.get_into(b"two", &mut small).unwrap(), None);
+        assert!(c.find_reader(b"two").unwrap().is_none());
+        assert_eq!(c.find_many(&[b"two"]).unwrap(), vec![None]);
+
+        // Without checked mode, the corrupted value is returned.
+        let mut c = Cdb::open_with(path, OpenOptions::new().checksums(&sums_path)).unwrap();
+        assert!(is_mismatch(c.verify_checksum()));
+        assert_eq!(c.find(b"one").unwrap(), b"Jello");
+
+        // Checked mode needs checksums.
+        assert!(Cdb::open_with(path, OpenOptions::new().checked(true)).is_err());
+        assert!(Cdb::open(path).unwrap().verify_checksum().is_err());
+
+        // Checksums survive a round trip.
+        let mut buf = Vec::new();
+        sums.write_to(&mut buf).unwrap();
+        let read = Checksums::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(read.digest(), sums.digest());
+        assert_eq!(read.len(), 5);
+        assert!(Checksums::read_from(&mut &buf[..buf.len() - 1]).is_err());
+
+        // Checksums of another database are refused.
+        let other = Path::new("checksums_other.cdb");
+        let _rem_other = RemovingPath::new(other);
+        let mut c = Cdb::new(other, |creator| {
+            creator.add(b"one", b"Hello").unwrap();
+        }).unwrap();
+        assert!(c.set_checksums(read).is_err());
+        assert!(Cdb::open_with(other, OpenOptions::new().checksums(&sums_path)).is_err());
+    }
+}
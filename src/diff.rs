@@ -0,0 +1,263 @@
+/*!
+ * Comparing two CDB databases.
+ *
+ * The `diff` function walks both databases sequentially and looks up each
+ * key in the other one, so only the values of a single key are ever held in
+ * memory at a time, along with the keys with several values that the scan
+ * is part-way through.  This makes it suitable for comparing databases with
+ * millions of records.
+ *
+ * # Patch format
+ *
+ * The differences can be written out as a patch with `CdbDiff::write_patch`.
+ * A patch borrows the record syntax of cdbmake input, with one record per
+ * line: `+klen,vlen:key->value` for a value only in the new database, and
+ * the same with a leading `-` instead of the `+` for a value only in the old
+ * one.  A changed key has all its old values removed, then all its new ones
+ * added.  The patch ends with an empty line, as cdbmake input does.  Since
+ * cdbmake has no way of removing records, a patch is only valid cdbmake input
+ * if it has no `-` lines.
+ */
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::{Cdb, CdbIterator};
+
+/// A single difference between two databases.
+///
+/// All values of a key are reported, in the order in which they were added,
+/// so multi-valued keys are compared as a whole.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEntry<'a> {
+    /// The key only exists in the new database.
+    Added {
+        /// The key that was added.
+        key: &'a [u8],
+        /// All of its values in the new database.
+        values: Vec<&'a [u8]>,
+    },
+
+    /// The key only exists in the old database.
+    Removed {
+        /// The key that was removed.
+        key: &'a [u8],
+        /// All of its values in the old database.
+        values: Vec<&'a [u8]>,
+    },
+
+    /// The key exists in both databases, but with different values.
+    Changed {
+        /// The key that was changed.
+        key: &'a [u8],
+        /// All of its values in the old database.
+        old: Vec<&'a [u8]>,
+        /// All of its values in the new database.
+        new: Vec<&'a [u8]>,
+    },
+}
+
+impl<'a> DiffEntry<'a> {
+    /**
+     * Returns the key that this entry refers to.
+     */
+    pub fn key(&self) -> &'a [u8] {
+        match *self {
+            DiffEntry::Added { key, .. } => key,
+            DiffEntry::Removed { key, .. } => key,
+            DiffEntry::Changed { key, .. } => key,
+        }
+    }
+
+    /**
+     * `write_patch(out)` writes this entry as records in the patch format
+     * described in the module documentation.
+     */
+    pub fn write_patch<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match *self {
+            DiffEntry::Added { key, ref values } => {
+                write_records(out, b'+', key, values)
+            },
+            DiffEntry::Removed { key, ref values } => {
+                write_records(out, b'-', key, values)
+            },
+            DiffEntry::Changed { key, ref old, ref new } => {
+                write_records(out, b'-', key, old)?;
+                write_records(out, b'+', key, new)
+            },
+        }
+    }
+}
+
+fn write_records<W: Write>(out: &mut W, sign: u8, key: &[u8], values: &[&[u8]]) -> io::Result<()> {
+    for val in values {
+        out.write_all(&[sign])?;
+        write!(out, "{},{}:", key.len(), val.len())?;
+        out.write_all(key)?;
+        out.write_all(b"->")?;
+        out.write_all(val)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// An iterator over the differences between two databases, created by
+/// `diff()`.
+///
+/// Removed and changed keys are reported first, in the order in which they
+/// appear in the old database, followed by added keys in the order in which
+/// they appear in the new database.
+pub struct CdbDiff<'a> {
+    old: &'a Cdb,
+    new: &'a Cdb,
+    old_records: CdbIterator<'a>,
+    new_records: CdbIterator<'a>,
+
+    // Keys with several records that were already reported on, with the
+    // number of their records the scan has yet to pass.
+    pending: HashMap<&'a [u8], usize>,
+}
+
+/**
+ * `diff(old, new)` returns an iterator over the keys that were added,
+ * removed or changed between the `old` and `new` databases.
+ */
+pub fn diff<'a>(old: &'a Cdb, new: &'a Cdb) -> CdbDiff<'a> {
+    CdbDiff {
+        old,
+        new,
+        old_records: old.iter(),
+        new_records: new.iter(),
+        pending: HashMap::new(),
+    }
+}
+
+// A key with several records shows up several times in a sequential scan.
+// `seen` says whether the key was already handled, and `expect` remembers how
+// many more of its records are to come after it was handled once, so that
+// only keys with several records are remembered, and only until the scan has
+// passed all of them.
+fn seen<'a>(pending: &mut HashMap<&'a [u8], usize>, key: &'a [u8]) -> bool {
+    match pending.get_mut(key) {
+        Some(left) => {
+            *left -= 1;
+            if *left == 0 {
+                pending.remove(key);
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+fn expect<'a>(pending: &mut HashMap<&'a [u8], usize>, key: &'a [u8], count: usize) {
+    if count > 1 {
+        pending.insert(key, count - 1);
+    }
+}
+
+impl<'a> CdbDiff<'a> {
+    /**
+     * `write_patch(out)` writes every remaining difference to `out` using
+     * `DiffEntry::write_patch`, followed by the empty line that ends a patch.
+     */
+    pub fn write_patch<W: Write>(self, out: &mut W) -> io::Result<()> {
+        for entry in self {
+            entry.write_patch(out)?;
+        }
+        out.write_all(b"\n")
+    }
+}
+
+impl<'a> Iterator for CdbDiff<'a> {
+    type Item = DiffEntry<'a>;
+
+    fn next(&mut self) -> Option<DiffEntry<'a>> {
+        for (key, _) in &mut self.old_records {
+            if seen(&mut self.pending, key) {
+                continue;
+            }
+
+            let old: Vec<&[u8]> = self.old.find_all(key).collect();
+            expect(&mut self.pending, key, old.len());
+            let new: Vec<&[u8]> = self.new.find_all(key).collect();
+            if new.is_empty() {
+                return Some(DiffEntry::Removed { key, values: old });
+            }
+            if old != new {
+                return Some(DiffEntry::Changed { key, old, new });
+            }
+        }
+
+        for (key, _) in &mut self.new_records {
+            if seen(&mut self.pending, key) {
+                continue;
+            }
+
+            let values: Vec<&[u8]> = self.new.find_all(key).collect();
+            expect(&mut self.pending, key, values.len());
+            if self.old.find_all(key).next().is_none() {
+                return Some(DiffEntry::Added { key, values });
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::super::Cdb;
+    use super::super::tests::RemovingPath;
+    use super::{diff, DiffEntry};
+
+    #[test]
+    fn test_diff() {
+        let old_path = Path::new("diff_old.cdb");
+        let _rem_old = RemovingPath::new(old_path);
+        let new_path = Path::new("diff_new.cdb");
+        let _rem_new = RemovingPath::new(new_path);
+
+        let old = Cdb::new(old_path, |creator| {
+            creator.add(b"same", b"1").unwrap();
+            creator.add(b"removed", b"2").unwrap();
+            creator.add(b"changed", b"3").unwrap();
+            creator.add(b"multi", b"a").unwrap();
+            creator.add(b"multi", b"b").unwrap();
+        }).unwrap();
+
+        let new = Cdb::new(new_path, |creator| {
+            creator.add(b"multi", b"a").unwrap();
+            creator.add(b"changed", b"4").unwrap();
+            creator.add(b"same", b"1").unwrap();
+            creator.add(b"multi", b"c").unwrap();
+            creator.add(b"added", b"5").unwrap();
+            creator.add(b"added", b"6").unwrap();
+        }).unwrap();
+
+        let entries: Vec<DiffEntry> = diff(&old, &new).collect();
+        assert_eq!(entries, vec![
+            DiffEntry::Removed { key: b"removed", values: vec![b"2"] },
+            DiffEntry::Changed { key: b"changed", old: vec![b"3"], new: vec![b"4"] },
+            DiffEntry::Changed { key: b"multi", old: vec![b"a", b"b"], new: vec![b"a", b"c"] },
+            DiffEntry::Added { key: b"added", values: vec![b"5", b"6"] },
+        ]);
+
+        let mut patch = Vec::new();
+        diff(&old, &new).write_patch(&mut patch).unwrap();
+        assert_eq!(&*patch, &b"-7,1:removed->2\n\
+                               -7,1:changed->3\n\
+                               +7,1:changed->4\n\
+                               -5,1:multi->a\n\
+                               -5,1:multi->b\n\
+                               +5,1:multi->a\n\
+                               +5,1:multi->c\n\
+                               +5,1:added->5\n\
+                               +5,1:added->6\n\
+                               \n"[..]);
+
+        assert_eq!(diff(&old, &old).count(), 0);
+    }
+}
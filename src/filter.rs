@@ -0,0 +1,352 @@
+/*!
+ * Bloom filters over the keys of a database, for fast negative lookups.
+ *
+ * A filter is built by `CdbCreator` when asked to with `set_filter`, and is
+ * stored in a separate file next to the database.  Once loaded into a `Cdb`,
+ * every lookup first checks the filter, and a key that the filter has never
+ * seen is reported as missing without touching the hash tables.
+ *
+ * A filter remembers a fingerprint of the database it was built for, which
+ * is the random id the creator stored in it, so that a filter left over from
+ * an older version of a database is refused instead of hiding its new keys.
+ * A database without an id, such as one made by another tool, is instead
+ * identified by a hash of its whole contents.
+ */
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::{CdbError, CdbErrorKind, CdbResult};
+
+const MAGIC: &[u8; 8] = b"TCDBFLT1";
+
+// Errors about malformed files are reported the same way TinyCDB does.
+fn protocol_error() -> CdbError {
+    CdbError::new(
+        "Invalid filter format",
+        CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)),
+    )
+}
+
+// 64-bit FNV-1a.  This is independent of the hash CDB itself uses, so that
+// keys which collide in the hash tables don't also collide in the filter.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, &c| (h ^ c as u64).wrapping_mul(0x100000001b3))
+}
+
+fn key_hash(key: &[u8]) -> u64 {
+    fnv1a(0xcbf29ce484222325, key)
+}
+
+// Identifies a database without an id by its contents.
+pub(crate) fn fingerprint(mem: &[u8]) -> u64 {
+    key_hash(mem)
+}
+
+// Collects the hashes of keys as they are added to a database.
+pub(crate) struct FilterBuilder {
+    path: PathBuf,
+    fp_rate: f64,
+    hashes: Vec<u64>,
+}
+
+impl FilterBuilder {
+    pub(crate) fn new(path: &Path, fp_rate: f64) -> FilterBuilder {
+        FilterBuilder {
+            path: path.to_path_buf(),
+            fp_rate,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8]) {
+        self.hashes.push(key_hash(key));
+    }
+
+    // Builds the filter for the finished database with the given
+    // fingerprint, and writes it out.
+    pub(crate) fn write(mut self, fingerprint: u64) -> CdbResult<()> {
+        self.hashes.sort_unstable();
+        self.hashes.dedup();
+
+        let filter = BloomFilter::build(&self.hashes, self.fp_rate, fingerprint);
+        let res = File::create(&self.path).and_then(|mut f| filter.write_to(&mut f));
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CdbError::new("Error writing filter", CdbErrorKind::IoError(e))),
+        }
+    }
+}
+
+/// A Bloom filter over all the keys of a database.
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    fingerprint: u64,
+    fp_rate: f64,
+    hashes: u32,
+    nbits: u64,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    fn build(keys: &[u64], fp_rate: f64, fingerprint: u64) -> BloomFilter {
+        // The usual optimal sizing for `n` keys: `-n ln p / (ln 2)^2` bits and
+        // `(m / n) ln 2` hash functions.
+        let ln2 = std::f64::consts::LN_2;
+        let n = std::cmp::max(keys.len(), 1) as f64;
+        let nbits = std::cmp::max((-n * fp_rate.ln() / (ln2 * ln2)).ceil() as u64, 64);
+        let hashes = std::cmp::max((nbits as f64 / n * ln2).round() as u32, 1);
+
+        let mut ret = BloomFilter {
+            fingerprint,
+            fp_rate,
+            hashes,
+            nbits,
+            bits: vec![0; nbits.div_ceil(64) as usize],
+        };
+        for &hash in keys {
+            for bit in ret.bit_positions(hash) {
+                ret.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        ret
+    }
+
+    // Derives all the bit positions for a key from a single hash, by double
+    // hashing with its two halves.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let h1 = hash & 0xffffffff;
+        let h2 = (hash >> 32) | 1;
+        let nbits = self.nbits;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+    }
+
+    /**
+     * `sidecar_path(db)` returns the conventional location of the filter for
+     * the database at `db`, which is the same path with `.filter` appended.
+     */
+    pub fn sidecar_path(db: &Path) -> PathBuf {
+        let mut path = db.as_os_str().to_owned();
+        path.push(".filter");
+        PathBuf::from(path)
+    }
+
+    /**
+     * `open(path)` reads a filter from the file at the given path.
+     */
+    pub fn open(path: &Path) -> CdbResult<BloomFilter> {
+        match File::open(path) {
+            Ok(mut f) => BloomFilter::read_from(&mut f),
+            Err(e) => Err(CdbError::new("Error opening filter", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * `read_from(input)` reads a filter in the format written by `write_to`.
+     */
+    pub fn read_from<R: Read>(input: &mut R) -> CdbResult<BloomFilter> {
+        let mut buf = Vec::new();
+        if let Err(e) = input.read_to_end(&mut buf) {
+            return Err(CdbError::new("Error reading filter", CdbErrorKind::IoError(e)));
+        }
+
+        if buf.len() < 36 || &buf[..8] != MAGIC {
+            return Err(protocol_error());
+        }
+        let u64_at = |pos: usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&buf[pos..pos + 8]);
+            u64::from_le_bytes(b)
+        };
+
+        let fingerprint = u64_at(8);
+        let fp_rate = f64::from_bits(u64_at(16));
+        let hashes = u32::from_le_bytes([buf[24], buf[25], buf[26], buf[27]]);
+        let nbits = u64_at(28);
+        let words = buf.len() - 36;
+        if hashes == 0 || nbits == 0 || words % 8 != 0 || nbits.div_ceil(64) != (words / 8) as u64 {
+            return Err(protocol_error());
+        }
+
+        let bits = buf[36..].chunks(8).map(|w| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(w);
+            u64::from_le_bytes(b)
+        }).collect();
+
+        Ok(BloomFilter {
+            fingerprint,
+            fp_rate,
+            hashes,
+            nbits,
+            bits,
+        })
+    }
+
+    /**
+     * `write_to(out)` writes the filter out, so that it can later be read
+     * back with `read_from`.  All integers are stored little-endian, as in
+     * the database itself.
+     */
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.fingerprint.to_le_bytes())?;
+        out.write_all(&self.fp_rate.to_bits().to_le_bytes())?;
+        out.write_all(&self.hashes.to_le_bytes())?;
+        out.write_all(&self.nbits.to_le_bytes())?;
+        for word in &self.bits {
+            out.write_all(&word.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /**
+     * `contains(key)` returns whether the key may be in the database.  A
+     * `false` result is definite, while a `true` result is wrong for roughly
+     * the configured false-positive rate of absent keys.
+     */
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.bit_positions(key_hash(key))
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /**
+     * Returns the false-positive rate that this filter was built for.
+     */
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    pub(crate) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::super::{Cdb, CdbCreator, OpenOptions};
+    use super::super::tests::RemovingPath;
+    use super::BloomFilter;
+
+    #[test]
+    fn test_filter() {
+        let path = Path::new("filter.cdb");
+        let _rem = RemovingPath::new(path);
+        let filter_path = BloomFilter::sidecar_path(path);
+        let _rem_filter = RemovingPath::new(&filter_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_filter(&filter_path, 0.01).unwrap();
+            for i in 0..1000 {
+                let key = format!("key{}", i);
+                creator.add(key.as_bytes(), b"val").unwrap();
+            }
+        });
+
+        let mut c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        assert_eq!(c.stats().unwrap().filter_fp_rate, Some(0.01));
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            assert!(c.exists(key.as_bytes()));
+        }
+        assert_eq!(c.find(b"nope"), None);
+        assert_eq!(c.find_all(b"nope").count(), 0);
+
+        let filter = BloomFilter::open(&filter_path).unwrap();
+        let false_positives = (0..10000)
+            .filter(|i| filter.contains(format!("missing{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        // Opening with the filter loads it again...
+        let c = Cdb::open_with(path, OpenOptions::new().filter(&filter_path)).unwrap();
+        assert_eq!(c.stats().unwrap().filter_fp_rate, Some(0.01));
+
+        // ... and without it, there is none.
+        let c = Cdb::open(path).unwrap();
+        assert_eq!(c.stats().unwrap().filter_fp_rate, None);
+    }
+
+    #[test]
+    fn test_filter_set_late() {
+        let path = Path::new("filter_late.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator: &mut CdbCreator| {
+            creator.add(b"one", b"1").unwrap();
+            assert!(creator.set_filter(Path::new("filter_late.cdb.filter"), 0.01).is_err());
+        });
+        assert!(res.is_ok());
+        assert!(!Path::new("filter_late.cdb.filter").exists());
+    }
+
+    #[test]
+    fn test_filter_stale() {
+        let path = Path::new("filter_stale.cdb");
+        let _rem = RemovingPath::new(path);
+        let filter_path = BloomFilter::sidecar_path(path);
+        let _rem_filter = RemovingPath::new(&filter_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_filter(&filter_path, 0.01).unwrap();
+            creator.add(b"one", b"1").unwrap();
+        });
+        assert!(res.is_ok());
+
+        // Rebuild the database without the filter, leaving the old one behind.
+        std::fs::remove_file(path).unwrap();
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"one", b"1").unwrap();
+            creator.add(b"two", b"2").unwrap();
+        });
+        assert!(res.is_ok());
+
+        assert!(Cdb::open_with(path, OpenOptions::new().filter(&filter_path)).is_err());
+    }
+
+    #[test]
+    fn test_filter_rebuilt() {
+        let path = Path::new("filter_rebuilt.cdb");
+        let _rem = RemovingPath::new(path);
+        let filter_path = BloomFilter::sidecar_path(path);
+        let _rem_filter = RemovingPath::new(&filter_path);
+        let old_filter_path = Path::new("filter_rebuilt.cdb.old");
+        let _rem_old_filter = RemovingPath::new(old_filter_path);
+
+        let build = |first: &str| {
+            Cdb::new(path, |creator| {
+                creator.set_filter(&filter_path, 0.01).unwrap();
+                creator.add(first.as_bytes(), b"val").unwrap();
+                for i in 1..100 {
+                    creator.add(format!("k{:03}", i).as_bytes(), b"val").unwrap();
+                }
+            }).unwrap();
+            std::fs::read(path).unwrap()
+        };
+
+        let old = build("k000");
+        std::fs::rename(&filter_path, old_filter_path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The two keys land in the same hash table, so the rebuilt database
+        // has the same table of contents and size.
+        let new = build("k088");
+        assert_eq!(old.len(), new.len());
+        assert_eq!(old[..2048], new[..2048]);
+
+        assert!(Cdb::open_with(path, OpenOptions::new().filter(old_filter_path)).is_err());
+        let mut c = Cdb::open_with(path, OpenOptions::new().filter(&filter_path)).unwrap();
+        assert_eq!(c.find(b"k088"), Some(&b"val"[..]));
+    }
+}
@@ -0,0 +1,105 @@
+/*!
+ * The primitives of the CDB file format.
+ *
+ * A CDB file starts with a table of contents of `TABLES` entries, each
+ * holding the position and number of slots of one hash table.  The records
+ * follow, each as a `RECORD_HEADER_SIZE`-byte header with the key and value
+ * lengths, then the key and the value.  The hash tables come last, as arrays
+ * of `SLOT_SIZE`-byte slots holding the hash of a key and the position of
+ * its record.  All integers are 32-bit little-endian.
+ *
+ * A key is placed in the table given by `table(hash(key))`, and a lookup
+ * starts probing that table at slot `slot(hash(key), n)`.
+ */
+
+/// The number of hash tables.
+pub const TABLES: usize = 256;
+
+/// The size of an entry in the table of contents: the position of a hash
+/// table and its number of slots.
+pub const TOC_ENTRY_SIZE: usize = 8;
+
+/// The size of the table of contents at the start of the file, which is
+/// also where the first record starts.
+pub const TOC_SIZE: usize = TABLES * TOC_ENTRY_SIZE;
+
+/// The size of a record header: the key length and the value length.
+pub const RECORD_HEADER_SIZE: usize = 8;
+
+/// The size of a hash table slot: the hash of a key and the position of its
+/// record, which is zero for an empty slot.
+pub const SLOT_SIZE: usize = 8;
+
+/// The initial value of the hash function.
+pub const HASH_INIT: u32 = 5381;
+
+/**
+ * `hash(key)` computes the hash of a key as CDB does, which is the same as
+ * TinyCDB's `cdb_hash`.
+ */
+pub fn hash(key: &[u8]) -> u32 {
+    key.iter().fold(HASH_INIT, |h, &c| (h.wrapping_add(h << 5)) ^ c as u32)
+}
+
+/**
+ * `table(hash)` returns which of the `TABLES` hash tables a key with the
+ * given hash is placed in.
+ */
+pub fn table(hash: u32) -> usize {
+    (hash as usize) % TABLES
+}
+
+/**
+ * `slot(hash, n)` returns the slot at which a lookup for a key with the
+ * given hash starts, in a hash table with `n` slots.  `n` must not be zero.
+ */
+pub fn slot(hash: u32, n: u32) -> u32 {
+    (hash >> 8) % n
+}
+
+/**
+ * `unpack(buf)` decodes the little-endian 32-bit integer at the start of
+ * `buf`, as TinyCDB's `cdb_unpack` does.  Panics if `buf` is shorter than
+ * four bytes.
+ */
+pub fn unpack(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/**
+ * `pack(num)` encodes a 32-bit integer as little-endian, as TinyCDB's
+ * `cdb_pack` does.
+ */
+pub fn pack(num: u32) -> [u8; 4] {
+    num.to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{c_uint, c_void};
+
+    use super::{hash, pack, slot, table, unpack};
+
+    #[test]
+    fn test_matches_tinycdb() {
+        let keys: [&[u8]; 4] = [b"", b"one", b"\xff\x00\x80", b"a much longer key than the others"];
+        for key in keys.iter() {
+            let expected = unsafe {
+                ffi::cdb_hash(key.as_ptr() as *const c_void, key.len() as c_uint)
+            };
+            assert_eq!(hash(key), expected);
+        }
+
+        for &num in [0, 1, 2048, 0x12345678, u32::MAX].iter() {
+            let mut buf = [0u8; 4];
+            unsafe { ffi::cdb_pack(num, buf.as_mut_ptr()) };
+            assert_eq!(pack(num), buf);
+            assert_eq!(unpack(&buf), unsafe { ffi::cdb_unpack(buf.as_ptr()) });
+            assert_eq!(unpack(&buf), num);
+        }
+
+        assert_eq!(hash(b"one"), 193_420_161);
+        assert_eq!(table(hash(b"one")), 129);
+        assert_eq!(slot(hash(b"one"), 4), 3);
+    }
+}
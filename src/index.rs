@@ -0,0 +1,370 @@
+/*!
+ * Ordered access to a database through a sorted index of its keys.
+ *
+ * CDB databases are addressed by hash, so on their own they can only answer
+ * exact lookups.  A `KeyIndex` lists the positions of all records sorted by
+ * key, which allows `Cdb::prefix` and `Cdb::range` to find the matching
+ * records with a binary search and return them in key order.
+ *
+ * An index can be built by `CdbCreator` when asked to with `set_index`, and
+ * is then stored in a separate file next to the database.  Like a
+ * `BloomFilter`, it remembers a fingerprint of the database it was built
+ * for.  If no index was loaded, one is built in memory the first time it is
+ * needed.
+ */
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use libc::{self, c_uint};
+
+use super::{Cdb, CdbError, CdbErrorKind, CdbResult};
+
+const MAGIC: &[u8; 8] = b"TCDBIDX1";
+
+// Errors about malformed files are reported the same way TinyCDB does.
+fn protocol_error(msg: &'static str) -> CdbError {
+    CdbError::new(msg, CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)))
+}
+
+/// A sorted index of the keys in a database.
+#[derive(Clone, Debug)]
+pub struct KeyIndex {
+    fingerprint: u64,
+    offsets: Vec<c_uint>,
+}
+
+impl KeyIndex {
+    /**
+     * `build(db)` builds the index for the given database.  This reads every
+     * key, and sorts them in memory.
+     */
+    pub fn build(db: &Cdb) -> CdbResult<KeyIndex> {
+        Ok(KeyIndex {
+            fingerprint: db.fingerprint()?,
+            offsets: sorted_offsets(db),
+        })
+    }
+
+    /**
+     * `sidecar_path(db)` returns the conventional location of the index for
+     * the database at `db`, which is the same path with `.index` appended.
+     */
+    pub fn sidecar_path(db: &Path) -> PathBuf {
+        let mut path = db.as_os_str().to_owned();
+        path.push(".index");
+        PathBuf::from(path)
+    }
+
+    /**
+     * `open(path)` reads an index from the file at the given path.
+     */
+    pub fn open(path: &Path) -> CdbResult<KeyIndex> {
+        match File::open(path) {
+            Ok(mut f) => KeyIndex::read_from(&mut f),
+            Err(e) => Err(CdbError::new("Error opening index", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * `read_from(input)` reads an index in the format written by `write_to`.
+     */
+    pub fn read_from<R: Read>(input: &mut R) -> CdbResult<KeyIndex> {
+        let mut buf = Vec::new();
+        if let Err(e) = input.read_to_end(&mut buf) {
+            return Err(CdbError::new("Error reading index", CdbErrorKind::IoError(e)));
+        }
+
+        if buf.len() < 16 || &buf[..8] != MAGIC || (buf.len() - 16) % 4 != 0 {
+            return Err(protocol_error("Invalid index format"));
+        }
+
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&buf[8..16]);
+        let offsets = buf[16..].chunks(4)
+            .map(|b| c_uint::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        Ok(KeyIndex {
+            fingerprint: u64::from_le_bytes(fingerprint),
+            offsets,
+        })
+    }
+
+    /**
+     * `write_to(out)` writes the index out, so that it can later be read
+     * back with `read_from`.
+     */
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.fingerprint.to_le_bytes())?;
+        for offset in &self.offsets {
+            out.write_all(&offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Writes the index out to the given path.
+    pub(crate) fn write_file(&self, path: &Path) -> CdbResult<()> {
+        match File::create(path).and_then(|mut f| self.write_to(&mut f)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(CdbError::new("Error writing index", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * Returns the number of records in the index.
+     */
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /**
+     * Returns whether the index has no records.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+}
+
+// Returns the offsets of all the records in the hash tables, sorted by key.
+// Records with the same key stay in the order in which they were added.
+fn sorted_offsets(db: &Cdb) -> Vec<c_uint> {
+    let mut offsets: Vec<c_uint> = db.record_offsets().collect();
+    offsets.sort_unstable_by(|&a, &b| {
+        let ka = db.record(a).map(|(k, _)| k);
+        let kb = db.record(b).map(|(k, _)| k);
+        ka.cmp(&kb).then(a.cmp(&b))
+    });
+    offsets
+}
+
+/// An iterator over records in key order, created by `Cdb::prefix` or
+/// `Cdb::range`.
+pub struct CdbRange<'a> {
+    underlying: &'a Cdb,
+    offsets: &'a [c_uint],
+}
+
+impl<'a> Iterator for CdbRange<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        while let Some((&offset, rest)) = self.offsets.split_first() {
+            self.offsets = rest;
+            if let Some(rec) = self.underlying.record(offset) {
+                return Some(rec);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.offsets.len()))
+    }
+}
+
+impl<'a> DoubleEndedIterator for CdbRange<'a> {
+    fn next_back(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        while let Some((&offset, rest)) = self.offsets.split_last() {
+            self.offsets = rest;
+            if let Some(rec) = self.underlying.record(offset) {
+                return Some(rec);
+            }
+        }
+        None
+    }
+}
+
+impl Cdb {
+    /**
+     * `set_index(index)` loads a key index built for this database, which
+     * is then used by `prefix()` and `range()`.  Fails if the index was
+     * built for a different database.
+     */
+    pub fn set_index(&mut self, index: KeyIndex) -> CdbResult<()> {
+        if index.fingerprint != self.fingerprint()? {
+            return Err(protocol_error("Index does not match database"));
+        }
+
+        self.index = OnceLock::from(index.offsets);
+        Ok(())
+    }
+
+    /**
+     * `prefix(prefix)` returns an iterator over all the records whose keys
+     * start with the given prefix, in key order.
+     */
+    pub fn prefix(&self, prefix: &[u8]) -> CdbRange<'_> {
+        let offsets = self.key_index();
+        let start = self.partition(offsets, |key| key < prefix);
+        let end = self.partition(offsets, |key| key < prefix || key.starts_with(prefix));
+        CdbRange {
+            underlying: self,
+            offsets: &offsets[start..end],
+        }
+    }
+
+    /**
+     * `range(keys)` returns an iterator over all the records whose keys
+     * fall within the given range, in key order.  For example,
+     * `db.range(&b"a"[..]..&b"c"[..])` returns all the keys starting with
+     * `a` or `b`.
+     */
+    pub fn range<'k, R>(&self, keys: R) -> CdbRange<'_>
+        where R: RangeBounds<&'k [u8]>
+    {
+        let offsets = self.key_index();
+        let start = match keys.start_bound() {
+            Bound::Included(&a) => self.partition(offsets, |key| key < a),
+            Bound::Excluded(&a) => self.partition(offsets, |key| key <= a),
+            Bound::Unbounded    => 0,
+        };
+        let end = match keys.end_bound() {
+            Bound::Included(&b) => self.partition(offsets, |key| key <= b),
+            Bound::Excluded(&b) => self.partition(offsets, |key| key < b),
+            Bound::Unbounded    => offsets.len(),
+        };
+        CdbRange {
+            underlying: self,
+            offsets: &offsets[start..std::cmp::max(start, end)],
+        }
+    }
+
+    // Returns the offsets of all the records, sorted by key, building them
+    // if no index was loaded.
+    fn key_index(&self) -> &[c_uint] {
+        self.index.get_or_init(|| sorted_offsets(self))
+    }
+
+    // Returns the index of the first record in `offsets` whose key doesn't
+    // satisfy `pred`, which must hold for a prefix of the keys.
+    fn partition<P>(&self, offsets: &[c_uint], pred: P) -> usize
+        where P: Fn(&[u8]) -> bool
+    {
+        offsets.partition_point(|&offset| match self.record(offset) {
+            Some((key, _)) => pred(key),
+            None           => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::super::{Cdb, OpenOptions};
+    use super::super::tests::RemovingPath;
+    use super::KeyIndex;
+
+    #[test]
+    fn test_prefix_and_range() {
+        let path = Path::new("index.cdb");
+        let _rem = RemovingPath::new(path);
+        let index_path = KeyIndex::sidecar_path(path);
+        let _rem_index = RemovingPath::new(&index_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_index(&index_path);
+            creator.add(b"10.0.1.0", b"c").unwrap();
+            creator.add(b"10.0.0.0", b"a").unwrap();
+            creator.add(b"192.168.0.0", b"d").unwrap();
+            creator.add(b"10.0.0.0", b"b").unwrap();
+            creator.add(b"10.1.0.0", b"e").unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+        assert_eq!(KeyIndex::open(&index_path).unwrap().len(), 5);
+
+        let vals: Vec<&[u8]> = c.prefix(b"10.0.").map(|(_, v)| v).collect();
+        assert_eq!(vals, vec![&b"a"[..], b"b", b"c"]);
+
+        let vals: Vec<&[u8]> = c.range(&b"10.0.1.0"[..]..&b"192"[..]).map(|(_, v)| v).collect();
+        assert_eq!(vals, vec![&b"c"[..], b"e"]);
+
+        let vals: Vec<&[u8]> = c.range(&b"10.1"[..]..).rev().map(|(_, v)| v).collect();
+        assert_eq!(vals, vec![&b"d"[..], b"e"]);
+
+        assert_eq!(c.prefix(b"172.").count(), 0);
+        assert_eq!(c.prefix(b"").count(), 5);
+
+        // The in-memory index gives the same results.
+        let c = Cdb::open(path).unwrap();
+        let keys: Vec<&[u8]> = c.prefix(b"10.").map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![&b"10.0.0.0"[..], b"10.0.0.0", b"10.0.1.0", b"10.1.0.0"]);
+
+        let c = Cdb::open_with(path, OpenOptions::new().index(&index_path)).unwrap();
+        assert_eq!(c.range(..).count(), 5);
+    }
+
+    #[test]
+    fn test_index_stale() {
+        let path = Path::new("index_stale.cdb");
+        let _rem = RemovingPath::new(path);
+        let index_path = KeyIndex::sidecar_path(path);
+        let _rem_index = RemovingPath::new(&index_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_index(&index_path);
+            creator.add(b"one", b"1").unwrap();
+        });
+        assert!(res.is_ok());
+
+        std::fs::remove_file(path).unwrap();
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"two", b"2").unwrap();
+        });
+        assert!(res.is_ok());
+
+        assert!(Cdb::open_with(path, OpenOptions::new().index(&index_path)).is_err());
+    }
+
+    #[test]
+    fn test_index_rebuilt() {
+        let path = Path::new("index_rebuilt.cdb");
+        let _rem = RemovingPath::new(path);
+        let index_path = KeyIndex::sidecar_path(path);
+        let _rem_index = RemovingPath::new(&index_path);
+
+        // The two keys land in the same hash table, so that whichever one a
+        // database is built with, it has the same table of contents and size.
+        let build = |first: &str, with_index: bool| {
+            let _ = std::fs::remove_file(path);
+            let c = Cdb::new(path, |creator| {
+                if with_index {
+                    creator.set_index(&index_path);
+                }
+                creator.add(first.as_bytes(), b"val").unwrap();
+                for i in 1..100 {
+                    creator.add(format!("k{:03}", i).as_bytes(), b"val").unwrap();
+                }
+            }).unwrap();
+            (c, std::fs::read(path).unwrap())
+        };
+
+        let (_, old) = build("k000", true);
+        let old_index = KeyIndex::open(&index_path).unwrap();
+        let (mut c, new) = build("k088", true);
+        assert_eq!(old.len(), new.len());
+        assert_eq!(old[..2048], new[..2048]);
+        assert!(c.set_index(old_index).is_err());
+        assert!(c.set_index(KeyIndex::open(&index_path).unwrap()).is_ok());
+        assert_eq!(c.prefix(b"k088").count(), 2);
+
+        // An index built later for a database without an id is told apart
+        // by the contents of the database.
+        let (c, old) = build("k000", false);
+        let old_index = KeyIndex::build(&c).unwrap();
+        let (mut c, new) = build("k088", false);
+        assert_eq!(old.len(), new.len());
+        assert_eq!(old[..2048], new[..2048]);
+        assert!(c.set_index(old_index).is_err());
+        assert!(c.set_index(KeyIndex::build(&c).unwrap()).is_ok());
+    }
+}
@@ -18,27 +18,94 @@
 #![warn(non_upper_case_globals)]
 #![warn(unused_qualifications)]
 
+#[cfg(feature = "checksum")]
+extern crate crc32c;
+#[cfg(feature = "signing")]
+extern crate ed25519_dalek;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
 extern crate libc;
+#[cfg(feature = "rand")]
+extern crate rand;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 extern crate tinycdb_sys as ffi;
 
 use std::borrow::Cow;
-use std::convert::Into;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::convert::{Into, TryFrom};
 use std::ffi::CString;
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::slice;
+use std::sync::OnceLock;
 
-use libc::{c_int, c_uint, c_void};
+use libc::{c_char, c_int, c_uint, c_void, mode_t};
 use libc::{open, close};
 use libc::{O_CREAT, O_EXCL, O_RDONLY, O_RDWR};
 
 // Re-export the private enums
 pub use ffi::CdbPutMode;
 
+#[cfg(feature = "tokio")]
+pub use async_cdb::{AsyncCdb, AsyncCdbCreator};
+#[cfg(feature = "checksum")]
+pub use checksum::Checksums;
+pub use diff::{diff, CdbDiff, DiffEntry};
+pub use filter::BloomFilter;
+pub use index::{CdbRange, KeyIndex};
+use filter::FilterBuilder;
+pub use metadata::RESERVED_PREFIX;
+pub use options::{Advice, OpenOptions};
+pub use pread::{PreadCdb, PreadIterator};
+pub use seek::{seek, SeekReader};
+#[cfg(feature = "signing")]
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+#[cfg(feature = "tokio")]
+mod async_cdb;
+#[cfg(feature = "checksum")]
+mod checksum;
+pub mod diff;
+mod filter;
+pub mod format;
+mod index;
+mod metadata;
+pub mod nss;
+mod options;
+#[cfg(feature = "rayon")]
+mod par;
+mod pread;
+mod seek;
+#[cfg(feature = "signing")]
+mod sign;
+
 /// Kinds of errors that can be encountered.
 #[derive(Debug)]
 pub enum CdbErrorKind {
     /// An error resulting from an underlying I/O error.
-    IoError(std::io::Error),
+    IoError(io::Error),
+
+    /// A `CdbCursor` that does not point at a record in the database.
+    InvalidCursor,
+
+    /// A key, a value or a whole database that doesn't fit in the CDB
+    /// format, where every length and position is 32 bits.
+    TooLarge,
+
+    /// A database whose contents don't match its checksums, see
+    /// `Checksums`.
+    ChecksumMismatch,
+
+    /// A database that isn't signed by any of the trusted keys, see
+    /// `Cdb::open_verified`.
+    InvalidSignature,
 
     // TODO: Split up actual I/O errors from errors that TinyCDB will return
     // in errno.
@@ -59,7 +126,7 @@ impl CdbError {
     where T: Into<Cow<'static, str>>
     {
         CdbError {
-            kind: kind,
+            kind,
             message: msg.into(),
         }
     }
@@ -71,77 +138,444 @@ impl CdbError {
     fn new_from_errno<T>(msg: T) -> CdbError
     where T: Into<Cow<'static, str>>
     {
-        CdbError::new(msg, CdbErrorKind::IoError(std::io::Error::last_os_error()))
+        CdbError::new(msg, CdbErrorKind::IoError(io::Error::last_os_error()))
+    }
+
+    /**
+     * Returns the kind of this error.
+     */
+    pub fn kind(&self) -> &CdbErrorKind {
+        &self.kind
+    }
+
+    /**
+     * Returns the human-readable message describing this error.
+     */
+    pub fn message(&self) -> &str {
+        &self.message
     }
 }
 
+impl std::fmt::Display for CdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            CdbErrorKind::IoError(ref e) => write!(f, "{}: {}", self.message, e),
+            _                            => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for CdbError {}
+
 /// A specialized Result type that might contain a CdbError.
 pub type CdbResult<T> = Result<T, CdbError>;
 
-/// A `CdbIterator` allows iterating over all the keys in a CDB database.
-pub struct CdbIterator<'a> {
-    underlying: &'a mut Cdb,
+// TinyCDB takes 32-bit lengths, so longer keys and values have to be caught
+// before they're silently truncated.
+fn c_len(buf: &[u8]) -> Option<c_uint> {
+    c_uint::try_from(buf.len()).ok()
+}
+
+// Walks the records of a database in order.  This is shared by all of the
+// public iterators, which only differ in which parts of each record they
+// hand out.
+struct Records<'a> {
+    underlying: &'a Cdb,
+    cdb: ffi::cdb,
     cptr: c_uint,
+    end: c_uint,
+
+    // The offsets of the records that aren't handed out, see
+    // `metadata::INTERNAL_KEYS`.
+    internal: Vec<c_uint>,
+
+    // The number of records left, which is only counted once asked for, as
+    // that takes a walk over the hash tables when starting from a cursor.
+    remaining: Cell<Option<usize>>,
 }
 
-// TODO: Move these into the Cdb struct.  Can't do that now because I ran into
-// some lifetime errors.
-impl<'a> CdbIterator<'a> {
-    unsafe fn get_key_slice(&self) -> &'a [u8] {
-        let len = self.underlying.cdb.cdb_keylen();
-        let ptr = ffi::cdb_get(
-            self.underlying.cdb_ptr(),
-            len,
-            self.underlying.cdb.cdb_keypos(),
-        ) as *const u8;
+impl<'a> Records<'a> {
+    // The iterator walks a scratch copy of the underlying `struct cdb`, so it
+    // never disturbs the state of the `Cdb` it was created from.
+    fn new(underlying: &'a Cdb) -> Records<'a> {
+        let mut iter = Records {
+            underlying,
+            cdb: underlying.scratch(),
+            cptr: 0,
+            end: c_uint::MAX,
+            internal: underlying.internal_offsets(),
+            remaining: Cell::new(Some(underlying.len())),
+        };
 
-        slice::from_raw_parts(ptr, len as usize)
+        unsafe {
+            ffi::cdb_seqinit(&mut iter.cptr, &mut iter.cdb);
+        }
+
+        iter
     }
 
-    unsafe fn get_data_slice(&self) -> &'a [u8] {
-        let len = self.underlying.cdb.cdb_datalen();
-        let ptr = ffi::cdb_get(
-            self.underlying.cdb_ptr(),
-            len,
-            self.underlying.cdb.cdb_datapos(),
-        ) as *const u8;
+    // Starts at the given cursor, which must have been validated.
+    fn new_at(underlying: &'a Cdb, cursor: CdbCursor) -> Records<'a> {
+        Records {
+            underlying,
+            cdb: underlying.scratch(),
+            cptr: cursor.offset,
+            end: c_uint::MAX,
+            internal: underlying.internal_offsets(),
+            remaining: Cell::new(None),
+        }
+    }
 
-        slice::from_raw_parts(ptr, len as usize)
+    // Walks the `count` records between the `start` and `end` offsets, which
+    // must both be record boundaries.
+    #[cfg(feature = "rayon")]
+    fn new_range(underlying: &'a Cdb, start: c_uint, end: c_uint, count: usize) -> Records<'a> {
+        Records {
+            underlying,
+            cdb: underlying.scratch(),
+            cptr: start,
+            end,
+            internal: underlying.internal_offsets(),
+            remaining: Cell::new(Some(count)),
+        }
+    }
+
+    fn cursor(&self) -> CdbCursor {
+        CdbCursor { offset: self.cptr }
     }
+
+    // Moves to the next record, returning whether there was one.  The
+    // internal records are skipped, and so are records zeroed out by
+    // `CdbCreator::remove(key, true)`, which are left in place as records
+    // with an empty key that the hash tables don't point at.
+    fn advance(&mut self) -> bool {
+        loop {
+            if self.cptr >= self.end {
+                self.remaining.set(Some(0));
+                return false
+            }
+            let start = self.cptr;
+
+            let ret = unsafe {
+                ffi::cdb_seqnext(
+                    &mut self.cptr,
+                    &mut self.cdb,
+                )
+            };
+
+            // TODO: should distinguish error condition from end-of-iteration
+            if ret <= 0 {
+                self.remaining.set(Some(0));
+                return false
+            }
+
+            if self.internal.contains(&start) {
+                continue
+            }
+            if self.cdb.cdb_keylen() == 0 && !self.is_indexed() {
+                continue
+            }
+            break
+        }
+
+        if let Some(remaining) = self.remaining.get() {
+            self.remaining.set(Some(remaining.saturating_sub(1)));
+        }
+        true
+    }
+
+    fn key(&self) -> &'a [u8] {
+        unsafe { self.underlying.get_slice(self.cdb.cdb_keypos(), self.cdb.cdb_keylen()) }
+    }
+
+    fn value(&self) -> &'a [u8] {
+        unsafe { self.underlying.get_slice(self.cdb.cdb_datapos(), self.cdb.cdb_datalen()) }
+    }
+
+    // Whether the hash tables point at the current record.
+    fn is_indexed(&self) -> bool {
+        let val = self.value();
+        self.underlying.table_find_all(self.key()).any(|found| found.as_ptr() == val.as_ptr())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = match self.remaining.get() {
+            Some(remaining) => remaining,
+            None            => {
+                let remaining = self.underlying.len_from(self.cptr);
+                self.remaining.set(Some(remaining));
+                remaining
+            },
+        };
+        (remaining, Some(remaining))
+    }
+}
+
+/// A `CdbCursor` records a position in the sequence of records of a
+/// database, so that an interrupted iteration can be resumed later with
+/// `Cdb::iter_from`.  It is simply the offset of a record in the file, and
+/// can be saved and restored with `offset()` and `from_offset()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CdbCursor {
+    offset: c_uint,
+}
+
+impl CdbCursor {
+    /**
+     * Creates a cursor from an offset previously returned by `offset()`.  The
+     * offset is only checked when the cursor is used.
+     */
+    pub fn from_offset(offset: u32) -> CdbCursor {
+        CdbCursor { offset }
+    }
+
+    /**
+     * Returns the offset in the file of the record this cursor points at.
+     */
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+/// A `CdbIterator` allows iterating over all the keys in a CDB database.
+pub struct CdbIterator<'a> {
+    records: Records<'a>,
 }
 
 impl<'a> Iterator for CdbIterator<'a> {
     type Item = (&'a [u8], &'a [u8]);
 
     fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
-        let ret = unsafe {
-            ffi::cdb_seqnext(
-                &mut self.cptr,
-                self.underlying.cdb_mut_ptr(),
-            )
-        };
+        if !self.records.advance() {
+            return None
+        }
+        Some((self.records.key(), self.records.value()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for CdbIterator<'a> {}
+
+impl<'a> CdbIterator<'a> {
+    /**
+     * Returns a cursor pointing at the next record this iterator will return.
+     */
+    pub fn cursor(&self) -> CdbCursor {
+        self.records.cursor()
+    }
+}
+
+/// A `CdbKeys` iterates over the keys of all the records in a CDB database.
+pub struct CdbKeys<'a> {
+    records: Records<'a>,
+}
+
+impl<'a> Iterator for CdbKeys<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if !self.records.advance() {
+            return None
+        }
+        Some(self.records.key())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for CdbKeys<'a> {}
 
-        // TODO: should distinguish error condition from end-of-iteration
-        if ret <= 0 {
+impl<'a> CdbKeys<'a> {
+    /**
+     * Returns a cursor pointing at the next record this iterator will return.
+     */
+    pub fn cursor(&self) -> CdbCursor {
+        self.records.cursor()
+    }
+}
+
+/// A `CdbValues` iterates over the values of all the records in a CDB
+/// database.
+pub struct CdbValues<'a> {
+    records: Records<'a>,
+}
+
+impl<'a> Iterator for CdbValues<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if !self.records.advance() {
             return None
         }
+        Some(self.records.value())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.records.size_hint()
+    }
+}
 
-        let v = unsafe { (self.get_key_slice(), self.get_data_slice()) };
-        Some(v)
+impl<'a> ExactSizeIterator for CdbValues<'a> {}
+
+impl<'a> CdbValues<'a> {
+    /**
+     * Returns a cursor pointing at the next record this iterator will return.
+     */
+    pub fn cursor(&self) -> CdbCursor {
+        self.records.cursor()
     }
+}
 
+/// A `CdbFindAll` iterates over every value stored under a single key, in
+/// the order in which they were added to the database.
+pub struct CdbFindAll<'a> {
+    underlying: &'a Cdb,
+
+    // `cdbf` holds pointers to both the scratch `struct cdb` and the key, so
+    // they are boxed to keep their addresses stable when the iterator moves.
+    cdbf: ffi::cdb_find,
+    cdb: Box<ffi::cdb>,
+    key: Box<[u8]>,
+    checked: bool,
+    done: bool,
+}
+
+impl<'a> Iterator for CdbFindAll<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.done {
+            return None
+        }
+
+        loop {
+            let res = unsafe { ffi::cdb_findnext(&mut self.cdbf) };
+
+            // TODO: should distinguish error condition from end-of-iteration
+            if res <= 0 {
+                self.done = true;
+                return None
+            }
+
+            // In checked mode, records that fail their checksum are skipped.
+            if !self.checked || self.underlying.value_ok(self.key.len() as c_uint, self.cdb.cdb_datapos()) {
+                break
+            }
+        }
+
+        unsafe {
+            Some(self.underlying.get_slice(self.cdb.cdb_datapos(), self.cdb.cdb_datalen()))
+        }
+    }
+}
+
+// Something that can copy bytes out of a database file by position.
+trait ReadAt {
+    // Copies `buf.len()` bytes starting at `pos` in the file into `buf`.
+    fn read_at(&self, buf: &mut [u8], pos: c_uint) -> io::Result<()>;
+}
+
+/// A `CdbValueReader` reads a single value from the database through
+/// `std::io::Read` and `std::io::Seek`, copying it out piece by piece rather
+/// than handing out a slice of the whole value.
+pub struct CdbValueReader<'a> {
+    underlying: &'a dyn ReadAt,
+    pos: c_uint,
+    len: c_uint,
+    offset: u64,
+}
+
+impl<'a> CdbValueReader<'a> {
+    // Note: deliberately private
+    fn new(underlying: &'a dyn ReadAt, pos: c_uint, len: c_uint) -> CdbValueReader<'a> {
+        CdbValueReader {
+            underlying,
+            pos,
+            len,
+            offset: 0,
+        }
+    }
+
+    /**
+     * Returns the total length of the value, in bytes.
+     */
+    pub fn len(&self) -> u64 {
+        self.len as u64
+    }
+
+    /**
+     * Returns whether the value is empty.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> Read for CdbValueReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.len as u64 {
+            return Ok(0)
+        }
+
+        let remaining = self.len as u64 - self.offset;
+        let n = std::cmp::min(remaining, buf.len() as u64) as usize;
+
+        // The offset is below `len`, so this can't overflow.
+        let pos = self.pos + self.offset as c_uint;
+        self.underlying.read_at(&mut buf[..n], pos)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Seek for CdbValueReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n)   => (n, 0),
+            SeekFrom::End(n)     => (self.len as u64, n),
+            SeekFrom::Current(n) => (self.offset, n),
+        };
+
+        let new = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        match new {
+            Some(n) => {
+                self.offset = n;
+                Ok(n)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
 }
 
 // Convert a Path instance to a C-style string
-fn path_as_c_str<T, F>(path: &Path, f: F) -> T
-    where F: Fn(*const i8) -> T
+fn path_as_c_str<T, F>(path: &Path, f: F) -> CdbResult<T>
+    where F: Fn(*const c_char) -> T
 {
-    // Convert to an OsStr
-    let ostr = path.as_os_str();
-    let str = ostr.to_str().unwrap();
-    let cstring = CString::new(str).unwrap();
-
-    f(cstring.as_ptr())
+    // Paths are passed through as raw bytes, so they don't need to be valid
+    // UTF-8, but they can't contain a NUL.
+    let bytes = path.as_os_str().as_bytes();
+    match CString::new(bytes) {
+        Ok(cstring) => Ok(f(cstring.as_ptr())),
+        Err(_) => Err(CdbError::new(
+            "Path contains a NUL byte",
+            CdbErrorKind::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path contains a NUL byte",
+            )),
+        )),
+    }
 }
 
 
@@ -149,6 +583,38 @@ fn path_as_c_str<T, F>(path: &Path, f: F) -> T
 pub struct Cdb {
     cdb: ffi::cdb,
     fd: c_int,
+
+    // Sorted offsets of all the records in the hash tables, built the first
+    // time a record is accessed by index.
+    offsets: OnceLock<Vec<c_uint>>,
+
+    filter: Option<BloomFilter>,
+
+    // Offsets of all the records sorted by key, either loaded from a
+    // `KeyIndex` or built the first time they're needed.
+    index: OnceLock<Vec<c_uint>>,
+
+    #[cfg(feature = "checksum")]
+    checksums: Option<Checksums>,
+
+    // Whether lookups check records against `checksums`.
+    #[cfg(feature = "checksum")]
+    checked: bool,
+}
+
+/// Statistics about an open database, as returned by `Cdb::stats`.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct CdbStats {
+    /// The number of records in the hash tables.  See `Cdb::len`.
+    pub records: usize,
+
+    /// The size of the database file, in bytes.
+    pub size: u64,
+
+    /// The false-positive rate that the loaded Bloom filter was built for,
+    /// or `None` if there is no filter.
+    pub filter_fp_rate: Option<f64>,
 }
 
 impl Cdb {
@@ -158,22 +624,97 @@ impl Cdb {
      * database could not be opened.
      */
     pub fn open(path: &Path) -> CdbResult<Box<Cdb>> {
+        Cdb::open_with(path, &OpenOptions::new())
+    }
+
+    /**
+     * `open_with(path, options)` will open the CDB database at the given file
+     * path, like `open`, and then apply the given options to the memory
+     * mapping of the database.
+     */
+    pub fn open_with(path: &Path, options: &OpenOptions) -> CdbResult<Box<Cdb>> {
         let fd = path_as_c_str(path, |path| unsafe {
             open(path, O_RDONLY, 0)
-        });
+        })?;
 
         if fd < 0 {
             return Err(CdbError::new_from_errno("Error opening file"));
         }
 
+        Cdb::init(fd, options)
+    }
+
+    /**
+     * `from_file(file)` will open the CDB database stored in an already-open
+     * file.  The `Cdb` takes ownership of the file, and closes it when it is
+     * dropped.
+     */
+    pub fn from_file(file: File) -> CdbResult<Box<Cdb>> {
+        Cdb::from_fd(file.into())
+    }
+
+    /**
+     * `from_fd(fd)` will open the CDB database referred to by the given file
+     * descriptor, which must be readable.  The `Cdb` takes ownership of the
+     * descriptor, and closes it when it is dropped.
+     */
+    pub fn from_fd(fd: OwnedFd) -> CdbResult<Box<Cdb>> {
+        Cdb::init(fd.into_raw_fd(), &OpenOptions::new())
+    }
+
+    // Note: takes ownership of the descriptor, even on failure.
+    fn init(fd: c_int, options: &OpenOptions) -> CdbResult<Box<Cdb>> {
         let mut ret = Box::new(Cdb {
-            fd: fd,
-            cdb: unsafe { std::mem::uninitialized() },
+            fd,
+            // All zeroes is `CDB_STATIC_INIT`, and a valid value for every
+            // field until `cdb_init` fills them in.
+            cdb: unsafe { std::mem::zeroed() },
+            offsets: OnceLock::new(),
+            filter: None,
+            index: OnceLock::new(),
+            #[cfg(feature = "checksum")]
+            checksums: None,
+            #[cfg(feature = "checksum")]
+            checked: false,
         });
 
         let err = unsafe { ffi::cdb_init(ret.cdb_mut_ptr(), fd) };
         if err < 0 {
-            return Err(CdbError::new_from_errno("Error initializing CDB"));
+            let err = CdbError::new_from_errno("Error initializing CDB");
+
+            // There's nothing to unmap, so only close the descriptor.
+            unsafe { close(fd) };
+            ret.fd = -1;
+            return Err(err);
+        }
+
+        options.apply(ret.mapping()?)?;
+        #[cfg(feature = "signing")]
+        {
+            if let Some(keys) = options.public_keys() {
+                ret.verify_signature(keys)?;
+            }
+        }
+        if let Some(path) = options.filter_path() {
+            ret.set_filter(BloomFilter::open(path)?)?;
+        }
+        if let Some(path) = options.index_path() {
+            ret.set_index(KeyIndex::open(path)?)?;
+        }
+        #[cfg(feature = "checksum")]
+        {
+            if let Some(path) = options.checksums_path() {
+                ret.set_checksums(Checksums::open(path)?)?;
+            }
+            if options.is_checked() {
+                if ret.checksums.is_none() {
+                    return Err(CdbError::new(
+                        "Checked lookups need checksums",
+                        CdbErrorKind::IoError(io::Error::from(io::ErrorKind::InvalidInput)),
+                    ));
+                }
+                ret.checked = true;
+            }
         }
 
         Ok(ret)
@@ -184,31 +725,80 @@ impl Cdb {
      * given closure is called with an instance of a `CdbCreator`, allowing the
      * closure to insert values into the CDB database.  Once the closure
      * returns, the database can no longer be updated.  The now-open database
-     * instance is then returned.
+     * instance is then returned, with its Bloom filter and key index loaded
+     * if the closure asked for them with `CdbCreator::set_filter` and
+     * `CdbCreator::set_index`.
      */
-    pub fn new<F>(path: &Path, mut create: F) -> CdbResult<Box<Cdb>>
+    pub fn new<F>(path: &Path, create: F) -> CdbResult<Box<Cdb>>
         where F: FnMut(&mut CdbCreator)
     {
+        Cdb::new_with_mode(path, 0o644, create)
+    }
+
+    /**
+     * `new_with_mode(path, mode, cb)` creates a new CDB database like `new`,
+     * but with the given permissions instead of `0o644`.  As with `open(2)`,
+     * the process's umask is applied to them.  Sidecar files are written
+     * with the default permissions, since they don't hold any values.
+     */
+    pub fn new_with_mode<F>(path: &Path, mode: mode_t, mut create: F) -> CdbResult<Box<Cdb>>
+        where F: FnMut(&mut CdbCreator)
+    {
+        let mut options = OpenOptions::new();
+
         // This is its own scope because we want it to be closed before trying
         // to re-open it below.
         {
             // TODO: create as temp file
-            let mut creator = match CdbCreator::new(path) {
-                Ok(c) => c,
-                Err(r) => return Err(r),
-            };
+            let mut creator = CdbCreator::new(path, mode)?;
 
             // Call the creation function
-            create(&mut *creator);
+            create(&mut creator);
+            if let Some(ref filter) = creator.filter {
+                options.filter(filter.path());
+            }
+            if let Some(ref index) = creator.index {
+                options.index(index);
+            }
+            #[cfg(feature = "checksum")]
+            {
+                if let Some(ref checksums) = creator.checksums {
+                    options.checksums(checksums);
+                }
+            }
 
             // Finalize the database.
-            creator.finalize();
+            creator.finalize()?;
         }
 
         // TODO: rename into place
 
         // Delegate to the real 'open' function.
-        Cdb::open(path)
+        Cdb::open_with(path, &options)
+    }
+
+    /**
+     * `close()` unmaps and closes the database, like dropping it does, but
+     * reports any errors from doing so.  The resources are released even if
+     * an error is returned.
+     */
+    pub fn close(mut self) -> CdbResult<()> {
+        let mem = self.cdb.cdb_mem() as *mut c_void;
+        let len = self.cdb.cdb_fsize() as usize;
+
+        // This is what `cdb_free` does, except that it ignores errors.
+        let unmapped = unsafe { libc::munmap(mem, len) };
+        let unmap_err = io::Error::last_os_error();
+        let closed = unsafe { close(self.fd) };
+        self.fd = -1;
+
+        if unmapped < 0 {
+            return Err(CdbError::new("Error unmapping database", CdbErrorKind::IoError(unmap_err)));
+        }
+        if closed < 0 {
+            return Err(CdbError::new_from_errno("Error closing file"));
+        }
+        Ok(())
     }
 
     #[inline]
@@ -228,31 +818,128 @@ impl Cdb {
      * will only return the value of the first key.
      */
     pub fn find(&mut self, key: &[u8]) -> Option<&[u8]> {
+        // A key that's too long can't be in the database.
+        let klen = c_len(key)?;
+        if !self.may_contain(key) {
+            return None
+        }
+
         let res = unsafe {
             ffi::cdb_find(
                 self.cdb_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
             )
         };
         if res <= 0 {
             return None
         }
+        if !self.value_ok(klen, self.cdb.cdb_datapos()) {
+            // In checked mode, go on to the next intact record for the key.
+            return self.find_all(key).next()
+        }
 
-        let len = self.cdb.cdb_datalen();
-        let ptr = unsafe {
-            ffi::cdb_get(
-                self.cdb_ptr(),
-                len,
-                self.cdb.cdb_datapos(),
-            ) as *const u8
+        unsafe {
+            Some(self.get_slice(self.cdb.cdb_datapos(), self.cdb.cdb_datalen()))
+        }
+    }
+
+    /**
+     * `find_reader(key)` searches the database for the given key, and, if
+     * it's found, returns a reader over the associated value.  This is
+     * useful for very large values, which can then be streamed elsewhere
+     * with `std::io::copy` without being mapped into a single slice.  As
+     * with `find`, only the value of the first key is returned.
+     */
+    pub fn find_reader(&self, key: &[u8]) -> CdbResult<Option<CdbValueReader<'_>>> {
+        let ret = self.locate(key)?.map(|(pos, len)| {
+            CdbValueReader::new(self, pos, len)
+        });
+        Ok(ret)
+    }
+
+    /**
+     * `find_into(key, buf)` searches the database for the given key, and, if
+     * it's found, replaces the contents of `buf` with the associated value.
+     * This allows a single buffer to be reused across many lookups.  The
+     * return value indicates whether the key was found; if it wasn't, `buf`
+     * is left empty.
+     */
+    pub fn find_into(&self, key: &[u8], buf: &mut Vec<u8>) -> CdbResult<bool> {
+        buf.clear();
+
+        let (pos, len) = match self.locate(key)? {
+            Some(v) => v,
+            None    => return Ok(false),
         };
 
-        unsafe {
-            Some(slice::from_raw_parts(ptr, len as usize))
+        buf.resize(len as usize, 0);
+        match self.read_at(buf, pos) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                buf.clear();
+                Err(CdbError::new("Error reading value", CdbErrorKind::IoError(e)))
+            },
         }
     }
 
+    /**
+     * `get_into(key, buf)` searches the database for the given key, and, if
+     * it's found, copies as much of the associated value as fits into `buf`.
+     * The full length of the value is returned, so the value was truncated
+     * if this is larger than `buf.len()`.
+     */
+    pub fn get_into(&self, key: &[u8], buf: &mut [u8]) -> CdbResult<Option<usize>> {
+        let (pos, len) = match self.locate(key)? {
+            Some(v) => v,
+            None    => return Ok(None),
+        };
+
+        let n = std::cmp::min(len as usize, buf.len());
+        match self.read_at(&mut buf[..n], pos) {
+            Ok(()) => Ok(Some(len as usize)),
+            Err(e) => Err(CdbError::new("Error reading value", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * `find_many(keys)` looks up several keys at once, returning the value of
+     * the first record for each key in the same order as the input.  Before
+     * resolving any of the keys, it asks the kernel to read in the hash table
+     * slots and records that the lookups will touch, so that a cold database
+     * incurs roughly one round of page faults instead of one per key.  For a
+     * database that is already in the page cache, the extra system calls make
+     * this slower than calling `find` repeatedly.
+     */
+    pub fn find_many(&self, keys: &[&[u8]]) -> CdbResult<Vec<Option<&[u8]>>> {
+        let hashes: Vec<u32> = keys.iter().map(|k| format::hash(k)).collect();
+
+        // First, the hash table slot where each lookup starts...
+        let slots: Vec<Option<&[u8]>> = hashes.iter()
+            .map(|&h| self.initial_slot(h))
+            .collect();
+        self.will_need(slots.iter().flatten().cloned());
+
+        // ... then the records those slots point to ...
+        let records = slots.iter().flatten().filter_map(|slot| {
+            match format::unpack(&slot[4..]) {
+                0   => None,
+                pos => self.get_checked(pos, 8),
+            }
+        });
+        self.will_need(records);
+
+        // ... and finally do the actual lookups.
+        let mut ret = Vec::with_capacity(keys.len());
+        for key in keys {
+            let val = self.locate(key)?.map(|(pos, len)| unsafe {
+                self.get_slice(pos, len)
+            });
+            ret.push(val);
+        }
+        Ok(ret)
+    }
+
     /**
      * `find_mut(key)` searches the database for the given key, and, if it's
      * found, will return the associated value as a `Vec<u8>`.  Note that,
@@ -260,10 +947,7 @@ impl Cdb {
      * `find_mut` will only return the value of the first key.
      */
     pub fn find_mut(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.find(key) {
-            Some(val) => Some(val.to_vec()),
-            None      => None,
-        }
+        self.find(key).map(|val| val.to_vec())
     }
 
     /**
@@ -272,72 +956,533 @@ impl Cdb {
      * allocate space for the returned value, and thus may be faster.
      */
     pub fn exists(&mut self, key: &[u8]) -> bool {
+        let klen = match c_len(key) {
+            Some(klen) if self.may_contain(key) => klen,
+            _ => return false,
+        };
+
         let res = unsafe {
             ffi::cdb_find(
                 self.cdb_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
             )
         };
         if res <= 0 {
-            false
-        } else {
-            true
+            return false
         }
+        self.value_ok(klen, self.cdb.cdb_datapos()) || self.find_all(key).next().is_some()
     }
 
     /**
-     * `iter()` returns an iterator over all the keys in the database.  Only
-     * one iterator for a database can be active at a time.
+     * `find_all(key)` returns an iterator over all the values stored under
+     * the given key, in the order in which they were added.  Unlike `find`,
+     * this only needs a shared borrow of the database, so any number of
+     * these iterators can be active at once.
      */
-    pub fn iter<'i>(&'i mut self) -> CdbIterator<'i> {
-        // Need to get around the fact that we're borrowing self as mutable
-        // twice - specifically, once for the CdbIterator, and once to pass to
-        // cdb_seqinit.
-        let cdbp = unsafe { self.cdb_mut_ptr() };
+    pub fn find_all(&self, key: &[u8]) -> CdbFindAll<'_> {
+        self.find_all_with(key, true)
+    }
+
+    // Like `find_all`, but going straight to the hash tables, without the
+    // filter or checksums.  Used for looking at how the file is laid out,
+    // which shouldn't depend on how the database was opened.
+    fn table_find_all(&self, key: &[u8]) -> CdbFindAll<'_> {
+        self.find_all_with(key, false)
+    }
 
-        let mut iter = CdbIterator {
+    fn find_all_with(&self, key: &[u8], checked: bool) -> CdbFindAll<'_> {
+        // A key that's too long can't be in the database, and isn't worth
+        // copying.
+        let klen = c_len(key);
+        let mut ret = CdbFindAll {
             underlying: self,
-            cptr: 0,
+            cdbf: unsafe { std::mem::zeroed() },
+            cdb: Box::new(self.scratch()),
+            key: if klen.is_some() { key.into() } else { Box::default() },
+            checked,
+            done: false,
+        };
+        let klen = match klen {
+            Some(klen) if !checked || self.may_contain(key) => klen,
+            _ => {
+                ret.done = true;
+                return ret;
+            },
         };
 
-        unsafe {
-            ffi::cdb_seqinit(&mut iter.cptr, cdbp);
+        let res = unsafe {
+            ffi::cdb_findinit(
+                &mut ret.cdbf,
+                &mut *ret.cdb,
+                ret.key.as_ptr() as *const c_void,
+                klen,
+            )
+        };
+        if res <= 0 {
+            ret.done = true;
         }
 
-        iter
+        ret
+    }
+
+    /**
+     * `iter()` returns an iterator over all the records in the database, in
+     * order.  Any number of these iterators can be active at once.
+     */
+    pub fn iter(&self) -> CdbIterator<'_> {
+        CdbIterator { records: Records::new(self) }
+    }
+
+    /**
+     * `iter_from(cursor)` returns an iterator over all the records in the
+     * database, starting from the one the given cursor points at.  An error
+     * is returned if the cursor doesn't point at a record in this database.
+     * Only records that can be found by key are recognized, so cursors
+     * pointing at records zeroed out with `CdbCreator::remove(key, true)` are
+     * rejected.  Resuming takes constant time; the number of records left is
+     * only counted, by walking the hash tables, if the iterator is asked for
+     * its `len()`.
+     */
+    pub fn iter_from(&self, cursor: CdbCursor) -> CdbResult<CdbIterator<'_>> {
+        if !self.is_record_start(cursor.offset)? {
+            return Err(CdbError::new(
+                format!("Cursor at offset {} does not point at a record", cursor.offset),
+                CdbErrorKind::InvalidCursor,
+            ));
+        }
+        Ok(CdbIterator { records: Records::new_at(self, cursor) })
+    }
+
+    /**
+     * `record_at(index)` returns the record with the given index, counting
+     * in file order from zero, or `None` if the index is out of range.  The
+     * first call builds an index of all record positions from the hash
+     * tables, which takes time and memory proportional to the number of
+     * records; later calls take constant time.  Records zeroed out with
+     * `CdbCreator::remove(key, true)` are not included.
+     */
+    pub fn record_at(&self, index: usize) -> Option<(&[u8], &[u8])> {
+        let offsets = self.offsets.get_or_init(|| {
+            let mut offsets: Vec<c_uint> = self.record_offsets().collect();
+            offsets.sort_unstable();
+            offsets
+        });
+
+        self.record(*offsets.get(index)?)
+    }
+
+    /**
+     * `sample(n, rng)` returns `n` distinct records chosen at random, or all
+     * of the records if there are fewer than `n`.  See `record_at` for the
+     * cost of the first call.
+     */
+    #[cfg(feature = "rand")]
+    pub fn sample<R: rand::Rng + ?Sized>(&self, n: usize, rng: &mut R) -> Vec<(&[u8], &[u8])> {
+        // Make sure the index is built, so the count is right.
+        self.record_at(0);
+        let len = self.offsets.get().map_or(0, |o| o.len());
+
+        rand::seq::index::sample(rng, len, std::cmp::min(n, len))
+            .into_iter()
+            .filter_map(|i| self.record_at(i))
+            .collect()
+    }
+
+    /**
+     * `keys()` returns an iterator over the keys of all the records in the
+     * database, in order.  This is cheaper than `iter()` when the values
+     * aren't needed.
+     */
+    pub fn keys(&self) -> CdbKeys<'_> {
+        CdbKeys { records: Records::new(self) }
+    }
+
+    /**
+     * `values()` returns an iterator over the values of all the records in
+     * the database, in order.  This is cheaper than `iter()` when the keys
+     * aren't needed.
+     */
+    pub fn values(&self) -> CdbValues<'_> {
+        CdbValues { records: Records::new(self) }
+    }
+
+    /**
+     * `len()` returns the number of records in the database.  This is derived
+     * from the sizes of the hash tables, without reading any records.  As
+     * when iterating, records zeroed out with `CdbCreator::remove(key, true)`
+     * and the metadata record aren't counted.
+     */
+    pub fn len(&self) -> usize {
+        // Both TinyCDB and the original cdbmake size each hash table at twice
+        // the number of records in it.
+        let toc = match self.get_checked(0, 2048) {
+            Some(toc) => toc,
+            None      => return 0,
+        };
+        let records: usize = toc.chunks(8).map(|entry| (format::unpack(&entry[4..]) / 2) as usize).sum();
+        records.saturating_sub(self.internal_offsets().len())
+    }
+
+    /**
+     * `is_empty()` returns whether the database has no records.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * `stats()` returns statistics about the database, such as its size and
+     * the false-positive rate of its Bloom filter, if one is loaded.
+     */
+    pub fn stats(&self) -> CdbResult<CdbStats> {
+        Ok(CdbStats {
+            records: self.len(),
+            size: self.mapping()?.len() as u64,
+            filter_fp_rate: self.filter.as_ref().map(|f| f.fp_rate()),
+        })
+    }
+
+    /**
+     * `set_filter(filter)` loads a Bloom filter built for this database, so
+     * that lookups for keys that aren't in the database can usually be
+     * answered without reading the hash tables.  Fails if the filter was
+     * built for a different database.  See `CdbCreator::set_filter`.
+     */
+    pub fn set_filter(&mut self, filter: BloomFilter) -> CdbResult<()> {
+        if filter.fingerprint() != self.fingerprint()? {
+            return Err(CdbError::new(
+                "Filter does not match database",
+                CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)),
+            ));
+        }
+
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    // Whether the key may be in the database, according to the filter.
+    #[inline]
+    fn may_contain(&self, key: &[u8]) -> bool {
+        match self.filter {
+            Some(ref filter) => filter.contains(key),
+            None             => true,
+        }
+    }
+
+    // Returns the key and value of the record at the given offset, if it's
+    // within the file.
+    fn record(&self, offset: c_uint) -> Option<(&[u8], &[u8])> {
+        let header = self.get_checked(offset, 8)?;
+        let klen = format::unpack(header);
+        let vlen = format::unpack(&header[4..]);
+        let key = self.get_checked(offset.checked_add(8)?, klen)?;
+        let val = self.get_checked(offset.checked_add(8)?.checked_add(klen)?, vlen)?;
+        Some((key, val))
+    }
+
+    // Identifies this database, so that sidecar files built for it can be
+    // told apart from those built for another one.  That's the id stored by
+    // its creator, or failing that a hash of the whole file.
+    fn fingerprint(&self) -> CdbResult<u64> {
+        match self.id() {
+            Some(id) => Ok(id),
+            None     => Ok(filter::fingerprint(self.mapping()?)),
+        }
+    }
+
+    // Returns the offsets of all the records that the hash tables point at,
+    // in no particular order, leaving out the internal records, as iteration
+    // does.
+    fn record_offsets(&self) -> impl Iterator<Item = c_uint> + '_ {
+        let internal = self.internal_offsets();
+        self.table_offsets().filter(move |rpos| !internal.contains(rpos))
+    }
+
+    // Like `record_offsets`, but including the internal records.
+    fn table_offsets(&self) -> impl Iterator<Item = c_uint> + '_ {
+        let toc = self.get_checked(0, 2048).unwrap_or(&[]);
+        toc.chunks(8)
+            .filter_map(move |entry| {
+                let pos = format::unpack(entry);
+                let n = format::unpack(&entry[4..]);
+                n.checked_mul(8).and_then(|len| self.get_checked(pos, len))
+            })
+            .flat_map(|table| table.chunks(8).map(|slot| format::unpack(&slot[4..])))
+            .filter(|&rpos| rpos != 0)
+    }
+
+    // Returns the number of records at or after the given offset, by counting
+    // the hash table slots that point at them.
+    fn len_from(&self, offset: c_uint) -> usize {
+        self.record_offsets().filter(|&rpos| rpos >= offset).count()
+    }
+
+    // Returns the offset of the end of the data section, where the hash
+    // tables start.  This mirrors `cdb_init`.
+    fn data_end(&self) -> CdbResult<c_uint> {
+        let mem = self.mapping()?;
+        let dend = format::unpack(mem);
+        if dend < 2048 {
+            Ok(2048)
+        } else if dend as usize >= mem.len() {
+            Ok(mem.len() as c_uint)
+        } else {
+            Ok(dend)
+        }
+    }
+
+    // Whether a record starts at the given offset, or it is the end of the
+    // data section.
+    fn is_record_start(&self, offset: c_uint) -> CdbResult<bool> {
+        let dend = self.data_end()?;
+        if offset == dend {
+            return Ok(true)
+        }
+        if offset < 2048 || offset > dend - 8 {
+            return Ok(false)
+        }
+
+        let header = match self.get_checked(offset, 8) {
+            Some(h) => h,
+            None    => return Ok(false),
+        };
+        let klen = format::unpack(header);
+        let vlen = format::unpack(&header[4..]);
+        if klen > dend - offset - 8 || vlen > dend - offset - 8 - klen {
+            return Ok(false)
+        }
+        let (key, val) = unsafe {
+            (self.get_slice(offset + 8, klen), self.get_slice(offset + 8 + klen, vlen))
+        };
+
+        // The offset is valid if looking up the key it would have leads back
+        // to it.
+        Ok(self.table_find_all(key).any(|found| found.as_ptr() == val.as_ptr()))
+    }
+
+    // Looks up the first record for the given key, returning the position
+    // and length of its value.
+    fn locate(&self, key: &[u8]) -> CdbResult<Option<(c_uint, c_uint)>> {
+        let klen = match c_len(key) {
+            Some(klen) if self.may_contain(key) => klen,
+            _ => return Ok(None),
+        };
+        let mut cdb = self.scratch();
+        let res = unsafe {
+            ffi::cdb_find(
+                &mut cdb,
+                key.as_ptr() as *const c_void,
+                klen,
+            )
+        };
+        match res {
+            x if x < 0 => Err(CdbError::new_from_errno("Error finding key")),
+            0          => Ok(None),
+            _ if !self.value_ok(klen, cdb.cdb_datapos()) => {
+                // In checked mode, go on to the next intact record for the
+                // key, as `find` does.
+                let mut found = self.find_all(key);
+                Ok(found.next().map(|val| (found.cdb.cdb_datapos(), val.len() as c_uint)))
+            },
+            _          => Ok(Some((cdb.cdb_datapos(), cdb.cdb_datalen()))),
+        }
+    }
+
+    // Without the `checksum` feature, there are no checksums to check records
+    // against.
+    #[cfg(not(feature = "checksum"))]
+    #[inline]
+    fn value_ok(&self, _klen: c_uint, _pos: c_uint) -> bool {
+        true
+    }
+
+    // Returns the whole mapped file.
+    fn mapping(&self) -> CdbResult<&[u8]> {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(self.fd, &mut st) } < 0 {
+            return Err(CdbError::new_from_errno("Error getting file size"));
+        }
+
+        // TinyCDB maps at most 4GiB, see `cdb_init`.
+        let size = std::cmp::min(st.st_size as u64, c_uint::MAX as u64) as c_uint;
+        match self.get_checked(0, size) {
+            Some(mem) => Ok(mem),
+            None      => Err(CdbError::new_from_errno("Error getting file mapping")),
+        }
+    }
+
+    // Returns the 8-byte hash table slot at which a lookup for a key with the
+    // given hash would start, if there is one.
+    fn initial_slot(&self, hash: u32) -> Option<&[u8]> {
+        let entry = format::table(hash) * format::TOC_ENTRY_SIZE;
+        let toc = self.get_checked(entry as c_uint, format::TOC_ENTRY_SIZE as c_uint)?;
+        let pos = format::unpack(toc);
+        let n = format::unpack(&toc[4..]);
+        if n == 0 {
+            return None
+        }
+        let slot = format::slot(hash, n).checked_mul(format::SLOT_SIZE as c_uint)?;
+        self.get_checked(pos.checked_add(slot)?, format::SLOT_SIZE as c_uint)
+    }
+
+    // Like `get_slice`, but for ranges that haven't been validated yet.
+    fn get_checked(&self, pos: c_uint, len: c_uint) -> Option<&[u8]> {
+        let ptr = unsafe { ffi::cdb_get(self.cdb_ptr(), len, pos) as *const u8 };
+        if ptr.is_null() {
+            return None
+        }
+        unsafe { Some(slice::from_raw_parts(ptr, len as usize)) }
+    }
+
+    // Hints to the kernel that the pages backing the given ranges will be
+    // needed soon.  This is only a hint, so errors are ignored.
+    fn will_need<'s, I>(&self, ranges: I)
+        where I: Iterator<Item = &'s [u8]>
+    {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        // Several ranges often share a page, so only advise each page once.
+        let mut pages: Vec<usize> = Vec::new();
+        for mem in ranges {
+            let start = mem.as_ptr() as usize & !(page - 1);
+            let end = mem.as_ptr() as usize + mem.len();
+            pages.extend((start..end).step_by(page));
+        }
+        pages.sort();
+        pages.dedup();
+
+        for addr in pages {
+            unsafe {
+                libc::madvise(addr as *mut c_void, page, libc::MADV_WILLNEED);
+            }
+        }
+    }
+
+    // Returns a bitwise copy of the underlying `struct cdb`.  The struct only
+    // holds the descriptor, the mapping and the position of the last record
+    // found, so a copy can be handed to the C lookup routines without
+    // disturbing `self`.  The copy must not outlive `self`.
+    #[inline]
+    fn scratch(&self) -> ffi::cdb {
+        unsafe { std::ptr::read(&self.cdb) }
+    }
+
+    // Returns the `len` bytes at `pos` in the mapped file.  The caller must
+    // ensure that the range was reported by TinyCDB itself.
+    #[inline]
+    unsafe fn get_slice(&self, pos: c_uint, len: c_uint) -> &[u8] {
+        let ptr = ffi::cdb_get(self.cdb_ptr(), len, pos) as *const u8;
+        slice::from_raw_parts(ptr, len as usize)
     }
 }
 
 impl Drop for Cdb {
     fn drop(&mut self) {
-        unsafe { close(self.fd) };
+        // Already released by `close()`.
+        if self.fd < 0 {
+            return
+        }
+
+        unsafe {
+            ffi::cdb_free(self.cdb_mut_ptr());
+            close(self.fd);
+        }
     }
 }
 
 unsafe impl Send for Cdb {}
 
+// Methods that take `&self` only ever read from the mapping, or work on
+// scratch copies of the `struct cdb`, so a `Cdb` can be shared by threads.
+unsafe impl Sync for Cdb {}
+
+impl ReadAt for Cdb {
+    fn read_at(&self, buf: &mut [u8], pos: c_uint) -> io::Result<()> {
+        let len = match c_len(buf) {
+            Some(len) => len,
+            None      => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let res = unsafe {
+            ffi::cdb_read(
+                self.cdb_ptr(),
+                buf.as_mut_ptr() as *mut c_void,
+                len,
+                pos,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+}
+
 /// The `CdbCreator` struct is used while building a new CDB instance.
 pub struct CdbCreator {
     cdbm: ffi::cdb_make,
     fd: c_int,
+    filter: Option<FilterBuilder>,
+    index: Option<PathBuf>,
+    #[cfg(feature = "checksum")]
+    checksums: Option<PathBuf>,
+    #[cfg(feature = "signing")]
+    signing_key: Option<SigningKey>,
+    added: bool,
+    // How large the finished file will be at most, given the records added
+    // so far.
+    size: u64,
+    metadata: BTreeMap<String, Vec<u8>>,
 }
 
 impl CdbCreator {
     // Note: deliberately private
-    fn new(path: &Path) -> CdbResult<Box<CdbCreator>> {
+    fn new(path: &Path, mode: mode_t) -> CdbResult<Box<CdbCreator>> {
         let fd = path_as_c_str(path, |path| unsafe {
-            // TODO: allow changing this mode
-            open(path, O_RDWR|O_CREAT|O_EXCL, 0o644)
-        });
+            open(path, O_RDWR|O_CREAT|O_EXCL, mode as c_uint)
+        })?;
 
         if fd < 0 {
             return Err(CdbError::new_from_errno("Error creating file"));
         }
 
+        CdbCreator::init(fd)
+    }
+
+    /**
+     * `from_file(file)` starts creating a new CDB database in an already-open
+     * file, which should be empty and positioned at its start.  The
+     * `CdbCreator` takes ownership of the file; call `finish()` once all
+     * records have been added to get it back.
+     */
+    pub fn from_file(file: File) -> CdbResult<Box<CdbCreator>> {
+        CdbCreator::from_fd(file.into())
+    }
+
+    /**
+     * `from_fd(fd)` starts creating a new CDB database in the file referred
+     * to by the given descriptor.  See `from_file()`.
+     */
+    pub fn from_fd(fd: OwnedFd) -> CdbResult<Box<CdbCreator>> {
+        CdbCreator::init(fd.into_raw_fd())
+    }
+
+    // Note: takes ownership of the descriptor, even on failure.
+    fn init(fd: c_int) -> CdbResult<Box<CdbCreator>> {
         let mut ret = Box::new(CdbCreator {
-            fd: fd,
-            cdbm: unsafe { std::mem::uninitialized() },
+            fd,
+            // Every field is an integer or a pointer, so all zeroes is valid
+            // until `cdb_make_start` fills them in.
+            cdbm: unsafe { std::mem::zeroed() },
+            filter: None,
+            index: None,
+            #[cfg(feature = "checksum")]
+            checksums: None,
+            #[cfg(feature = "signing")]
+            signing_key: None,
+            added: false,
+            size: format::TOC_SIZE as u64,
+            metadata: BTreeMap::new(),
         });
 
         let err = unsafe {
@@ -350,6 +1495,20 @@ impl CdbCreator {
         Ok(ret)
     }
 
+    /**
+     * `finish()` writes out the hash tables, completing the database, and
+     * returns the underlying file.  This is only needed for creators made
+     * with `from_file()` or `from_fd()`; `Cdb::new` takes care of it
+     * otherwise.
+     */
+    pub fn finish(mut self: Box<Self>) -> CdbResult<File> {
+        self.finalize()?;
+
+        // Hand the descriptor back instead of closing it on drop.
+        let fd = std::mem::replace(&mut self.fd, -1);
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
     /*
     fn cdbm_ptr(&self) -> *const ffi::cdb_make {
         &self.cdbm as *const ffi::cdb_make
@@ -361,29 +1520,178 @@ impl CdbCreator {
         &mut self.cdbm
     }
 
-    fn finalize(&mut self) {
-        unsafe { ffi::cdb_make_finish(self.cdbm_mut_ptr()); }
+    fn finalize(&mut self) -> CdbResult<()> {
+        if !self.metadata.is_empty() {
+            let val = metadata::encode(&std::mem::take(&mut self.metadata));
+            self.add_record(metadata::METADATA_KEY, &val)?;
+        }
+
+        // Tie the sidecar files to this particular database, so that they
+        // can't be loaded with a rebuilt one.
+        let sidecars = self.filter.is_some() || self.index.is_some();
+        #[cfg(feature = "checksum")]
+        let sidecars = sidecars || self.checksums.is_some();
+        if sidecars {
+            self.add_record(metadata::ID_KEY, &metadata::new_id().to_le_bytes())?;
+        }
+
+        let res = unsafe { ffi::cdb_make_finish(self.cdbm_mut_ptr()) };
+        if res < 0 {
+            return Err(CdbError::new_from_errno("Error finishing CDB"));
+        }
+
+        // The signature goes first, since it changes the file, which the
+        // checksums below cover.
+        #[cfg(feature = "signing")]
+        self.sign()?;
+
+        if sidecars {
+            let db = self.read_back()?;
+            if let Some(filter) = self.filter.take() {
+                filter.write(db.fingerprint()?)?;
+            }
+            if let Some(path) = self.index.take() {
+                KeyIndex::build(&db)?.write_file(&path)?;
+            }
+            #[cfg(feature = "checksum")]
+            {
+                if let Some(path) = self.checksums.take() {
+                    Checksums::build(&db)?.write_file(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Opens the finished database through its own descriptor.
+    fn read_back(&self) -> CdbResult<Box<Cdb>> {
+        let fd = unsafe { libc::dup(self.fd) };
+        if fd < 0 {
+            return Err(CdbError::new_from_errno("Error duplicating descriptor"));
+        }
+        Cdb::init(fd, &OpenOptions::new())
+    }
+
+    /**
+     * `set_filter(path, fp_rate)` makes the creator build a Bloom filter
+     * over all the keys in the database, which is written to `path` once the
+     * database is finished.  The filter is sized so that lookups of absent
+     * keys get past it at roughly the given rate, e.g. `0.01` for 1%; a lower
+     * rate means a larger filter.  This must be called before any records are
+     * added.  `BloomFilter::sidecar_path` gives the usual place to put it.
+     */
+    pub fn set_filter(&mut self, path: &Path, fp_rate: f64) -> CdbResult<()> {
+        if self.added {
+            return Err(CdbError::new(
+                "Filter must be set before adding records",
+                CdbErrorKind::IoError(io::Error::from(io::ErrorKind::InvalidInput)),
+            ));
+        }
+        if !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(CdbError::new(
+                "Filter false-positive rate must be between 0 and 1",
+                CdbErrorKind::IoError(io::Error::from(io::ErrorKind::InvalidInput)),
+            ));
+        }
+
+        self.filter = Some(FilterBuilder::new(path, fp_rate));
+        Ok(())
+    }
+
+    /**
+     * `set_index(path)` makes the creator build a `KeyIndex` of the database
+     * once it is finished, and write it to `path`.  This needs to read the
+     * finished database back, so a creator made with `from_file()` or
+     * `from_fd()` must have been given a readable file.
+     * `KeyIndex::sidecar_path` gives the usual place to put it.
+     */
+    pub fn set_index(&mut self, path: &Path) {
+        self.index = Some(path.to_path_buf());
+    }
+
+    /**
+     * `set_checksums(path)` makes the creator compute `Checksums` of the
+     * database once it is finished, and write them to `path`.  As with
+     * `set_index`, a creator made with `from_file()` or `from_fd()` must
+     * have been given a readable file.  `Checksums::sidecar_path` gives the
+     * usual place to put them.  Available with the `checksum` feature.
+     */
+    #[cfg(feature = "checksum")]
+    pub fn set_checksums(&mut self, path: &Path) {
+        self.checksums = Some(path.to_path_buf());
+    }
+
+    // Checks that a record fits in the format before any of it is written,
+    // and counts it against the 4GiB limit on the size of the file.  Each
+    // record also takes two slots in the hash tables.  Records that are later
+    // removed or replaced are still counted, so the limit errs on the safe
+    // side.
+    fn reserve(&mut self, key: &[u8], val: &[u8]) -> CdbResult<(c_uint, c_uint)> {
+        let klen = CdbCreator::key_len(key)?;
+        let vlen = match c_len(val) {
+            Some(vlen) => vlen,
+            None       => return Err(CdbError::new("Value is larger than 4GiB", CdbErrorKind::TooLarge)),
+        };
+
+        let size = self.size
+            + (format::RECORD_HEADER_SIZE + 2 * format::SLOT_SIZE) as u64
+            + klen as u64
+            + vlen as u64;
+        if size > u32::MAX as u64 {
+            return Err(CdbError::new("Database would be larger than 4GiB", CdbErrorKind::TooLarge));
+        }
+
+        self.size = size;
+        Ok((klen, vlen))
+    }
+
+    fn key_len(key: &[u8]) -> CdbResult<c_uint> {
+        match c_len(key) {
+            Some(klen) => Ok(klen),
+            None       => Err(CdbError::new("Key is larger than 4GiB", CdbErrorKind::TooLarge)),
+        }
+    }
+
+    // Records that a key was added, so that it ends up in the filter.
+    fn added(&mut self, key: &[u8]) {
+        self.added = true;
+        if let Some(ref mut filter) = self.filter {
+            filter.add(key);
+        }
     }
 
     /**
      * `add(key, val)` adds the given key/value pair to the database, silently
      * overwriting any previously-existing value.  It returns whether or not
      * the operation succeeded.  Note that if this call panics, it is unsafe to
-     * continue building the database.
+     * continue building the database.  Keys starting with `RESERVED_PREFIX`
+     * are refused.
      */
     pub fn add(&mut self, key: &[u8], val: &[u8]) -> CdbResult<()> {
+        if metadata::is_reserved(key) {
+            return Err(metadata::reserved_key_error());
+        }
+        self.add_record(key, val)
+    }
+
+    fn add_record(&mut self, key: &[u8], val: &[u8]) -> CdbResult<()> {
+        let (klen, vlen) = self.reserve(key, val)?;
         let res = unsafe {
             ffi::cdb_make_add(
                 self.cdbm_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
                 val.as_ptr() as *const c_void,
-                val.len() as c_uint,
+                vlen,
             )
         };
         match res {
             x if x < 0 => Err(CdbError::new_from_errno("Error adding key/value")),
-            _          => Ok(()),
+            _          => {
+                self.added(key);
+                Ok(())
+            },
         }
     }
 
@@ -393,16 +1701,17 @@ impl CdbCreator {
      * library flushing the internal buffer to disk on every call.
      */
     pub fn exists(&mut self, key: &[u8]) -> CdbResult<bool> {
+        let klen = CdbCreator::key_len(key)?;
         let res = unsafe {
             ffi::cdb_make_exists(
                 self.cdbm_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
             )
         };
         match res {
             x if x < 0  => Err(CdbError::new_from_errno("Error checking if key exists")),
-            x if x == 0 => Ok(false),
+            0           => Ok(false),
             _           => Ok(true),
         }
     }
@@ -423,17 +1732,18 @@ impl CdbCreator {
      */
     pub fn remove(&mut self, key: &[u8], zero: bool) -> CdbResult<bool> {
         let mode = if zero { ffi::CdbFindMode::Fill0 } else { ffi::CdbFindMode::Remove };
+        let klen = CdbCreator::key_len(key)?;
         let res = unsafe {
             ffi::cdb_make_find(
                 self.cdbm_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
                 mode,
             )
         };
         match res {
             x if x < 0  => Err(CdbError::new_from_errno("Error removing key")),
-            x if x == 0 => Ok(false),
+            0           => Ok(false),
             _           => Ok(true),
         }
     }
@@ -443,30 +1753,39 @@ impl CdbCreator {
      * configurable behaviour if the key already exists.  See the documentation
      * on `CdbPutMode` for more information on the options available.
      * The return value from this function indicates whether or not any existing
-     * keys were found in the database during the put operation.
+     * keys were found in the database during the put operation.  As with
+     * `add`, keys starting with `RESERVED_PREFIX` are refused.
      */
     pub fn put(&mut self, key: &[u8], val: &[u8], mode: CdbPutMode) -> CdbResult<bool> {
+        if metadata::is_reserved(key) {
+            return Err(metadata::reserved_key_error());
+        }
+
+        let (klen, vlen) = self.reserve(key, val)?;
         let res = unsafe {
             ffi::cdb_make_put(
                 self.cdbm_mut_ptr(),
                 key.as_ptr() as *const c_void,
-                key.len() as c_uint,
+                klen,
                 val.as_ptr() as *const c_void,
-                val.len() as c_uint,
+                vlen,
                 mode,
             )
         };
-        match res {
-            x if x < 0  => Err(CdbError::new_from_errno("Error putting key/value")),
-            x if x == 0 => Ok(false),
-            _           => Ok(true),
+        if res < 0 {
+            return Err(CdbError::new_from_errno("Error putting key/value"));
         }
+
+        self.added(key);
+        Ok(res > 0)
     }
 }
 
 impl Drop for CdbCreator {
     fn drop(&mut self) {
-        unsafe { close(self.fd) };
+        if self.fd >= 0 {
+            unsafe { close(self.fd) };
+        }
     }
 }
 
@@ -487,6 +1806,7 @@ mod tests {
     use super::ffi;
 
     // De-base64s and decompresses
+    #[allow(clippy::single_match)]
     fn decompress_and_write(input: &[u8], path: &Path) {
         let raw = match input.from_base64() {
             Err(why) => panic!("Could not decode base64: {:?}", why),
@@ -513,7 +1833,7 @@ mod tests {
 
     // Helper to remove test files after a test is finished, even if the test
     // panic!()s
-    struct RemovingPath {
+    pub struct RemovingPath {
         underlying: PathBuf,
     }
 
@@ -526,6 +1846,7 @@ mod tests {
     }
 
     impl Drop for RemovingPath {
+        #[allow(clippy::single_match)]
         fn drop(&mut self) {
             match fs::remove_file(&self.underlying) {
                 Err(why) => println!("Couldn't remove temp file: {:?}", why),
@@ -541,6 +1862,7 @@ mod tests {
         f(path);
     }
 
+    #[allow(clippy::needless_borrow)]
     fn with_test_file<F>(input: &[u8], name: &str, mut f: F)
         where F: FnMut(&Path)
     {
@@ -554,6 +1876,7 @@ mod tests {
     // Simple compressed/base64'd CDB that contains the key/values:
     //      "one" --> "Hello"
     //      "two" --> "Goodbye"
+    #[allow(clippy::redundant_static_lifetimes, unused_parens)]
     static HELLO_CDB: &'static [u8] = (
         b"BCJNGERAXl4AAAAxIggAAQAPCAD/MlMCAAAAMlABDwgA//+jAMACE0LAAg8IAP///9jw\
           AQMAAAAFAAAAb25lSGVsbG8QAPMEBwAAAHR3b0dvb2RieWUpYIcLEBYECAIAgIFbhwsA\
@@ -603,6 +1926,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn test_iteration() {
         with_test_file(HELLO_CDB, "iter.cdb", |path| {
             let mut c = match Cdb::open(path) {
@@ -628,6 +1952,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_simple_create() {
         let mut ran = false;
 
@@ -647,6 +1972,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_add_and_exists() {
         let path = Path::new("add.cdb");
         let _rem = RemovingPath::new(&path);
@@ -680,6 +2006,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_remove() {
         let path = Path::new("remove.cdb");
         let _rem = RemovingPath::new(&path);
@@ -714,6 +2041,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow, clippy::borrow_deref_ref)]
     fn test_put() {
         let path = Path::new("put.cdb");
         let _rem = RemovingPath::new(&path);
@@ -750,6 +2078,497 @@ mod tests {
     }
 
     #[test]
+    fn test_find_all() {
+        let path = Path::new("find_all.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"foo", b"one").unwrap();
+            creator.add(b"bar", b"other").unwrap();
+            creator.add(b"foo", b"two").unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let vals: Vec<&[u8]> = c.find_all(b"foo").collect();
+        assert_eq!(vals, vec![&b"one"[..], &b"two"[..]]);
+        assert_eq!(c.find_all(b"missing").count(), 0);
+    }
+
+    #[test]
+    fn test_find_reader() {
+        use std::io::{Seek, SeekFrom};
+
+        let path = Path::new("find_reader.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let big: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"big", &big).unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let mut reader = match c.find_reader(b"big") {
+            Ok(Some(r)) => r,
+            Ok(None) => panic!("Could not find 'big' in CDB"),
+            Err(why) => panic!("Could not look up: {:?}", why),
+        };
+        assert_eq!(reader.len(), big.len() as u64);
+
+        let mut out = Vec::new();
+        std::io::copy(&mut reader, &mut out).unwrap();
+        assert_eq!(out, big);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), big.len() as u64 - 4);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &big[big.len() - 4..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-100001)).is_err());
+
+        assert!(c.find_reader(b"missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_find_into_and_get_into() {
+        let path = Path::new("find_into.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"foo", b"bar").unwrap();
+            creator.add(b"long", b"a longer value").unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let mut buf = Vec::new();
+        assert!(c.find_into(b"long", &mut buf).unwrap());
+        assert_eq!(&*buf, b"a longer value");
+        assert!(c.find_into(b"foo", &mut buf).unwrap());
+        assert_eq!(&*buf, b"bar");
+        assert!(!c.find_into(b"missing", &mut buf).unwrap());
+        assert!(buf.is_empty());
+
+        let mut small = [0u8; 8];
+        assert_eq!(c.get_into(b"foo", &mut small).unwrap(), Some(3));
+        assert_eq!(&small[..3], b"bar");
+        assert_eq!(c.get_into(b"long", &mut small).unwrap(), Some(14));
+        assert_eq!(&small[..], b"a longer");
+        assert_eq!(c.get_into(b"missing", &mut small).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_many() {
+        let path = Path::new("find_many.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..1000 {
+                let key = format!("key{}", i);
+                let val = format!("val{}", i);
+                creator.add(key.as_bytes(), val.as_bytes()).unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let keys: Vec<&[u8]> = vec![b"key10", b"missing", b"key999", b"key0"];
+        let vals = c.find_many(&keys).unwrap();
+        assert_eq!(vals, vec![Some(&b"val10"[..]), None, Some(&b"val999"[..]), Some(&b"val0"[..])]);
+    }
+
+    #[test]
+    fn test_open_with() {
+        use super::{Advice, OpenOptions};
+
+        let path = Path::new("open_with.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"foo", b"bar").unwrap();
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+
+        let opts = [
+            OpenOptions::new().advice(Advice::Random).clone(),
+            OpenOptions::new().advice(Advice::Sequential).populate(true).clone(),
+            OpenOptions::new().huge_pages(true).clone(),
+        ];
+        for opt in opts.iter() {
+            let mut c = match Cdb::open_with(path, opt) {
+                Ok(c) => c,
+                Err(why) => panic!("Could not open with {:?}: {:?}", opt, why),
+            };
+            assert_eq!(c.find(b"foo"), Some(&b"bar"[..]));
+        }
+    }
+
+    #[test]
+    fn test_from_file() {
+        use std::fs::OpenOptions;
+
+        use super::CdbCreator;
+
+        let path = Path::new("from_file.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path).unwrap();
+        let mut creator = match CdbCreator::from_file(file) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+        creator.add(b"foo", b"bar").unwrap();
+        let file = match creator.finish() {
+            Ok(f) => f,
+            Err(why) => panic!("Could not finish: {:?}", why),
+        };
+
+        // The returned file is still open and can be read back directly.
+        let mut c = match Cdb::from_file(file) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not open CDB: {:?}", why),
+        };
+        assert_eq!(c.find(b"foo"), Some(&b"bar"[..]));
+
+        let mut c = match Cdb::from_fd(File::open(path).unwrap().into()) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not open CDB: {:?}", why),
+        };
+        assert_eq!(c.find(b"foo"), Some(&b"bar"[..]));
+    }
+
+    #[test]
+    fn test_unusual_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // Not valid UTF-8.
+        let path = Path::new(OsStr::from_bytes(b"latin1_\xe9t\xe9.cdb"));
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"foo", b"bar").unwrap();
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+
+        let mut c = match Cdb::open(path) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not open CDB: {:?}", why),
+        };
+        assert_eq!(c.find(b"foo"), Some(&b"bar"[..]));
+
+        let path = Path::new(OsStr::from_bytes(b"nul\0.cdb"));
+        assert!(Cdb::open(path).is_err());
+        assert!(Cdb::new(path, |_creator| {}).is_err());
+    }
+
+    #[test]
+    fn test_keys_values_len() {
+        let path = Path::new("keys_values.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                let val = format!("val{}", i);
+                creator.add(key.as_bytes(), val.as_bytes()).unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        assert_eq!(c.len(), 100);
+
+        let mut keys = c.keys();
+        assert_eq!(keys.len(), 100);
+        assert_eq!(keys.next(), Some(&b"key0"[..]));
+        assert_eq!(keys.len(), 99);
+        assert_eq!(keys.count(), 99);
+
+        let values: Vec<&[u8]> = c.values().collect();
+        assert_eq!(values.len(), 100);
+        assert_eq!(values[99], b"val99");
+
+        assert_eq!(c.iter().len(), 100);
+    }
+
+    #[test]
+    fn test_iter_zeroed_records() {
+        let path = Path::new("iter_zeroed.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"one", b"1").unwrap();
+            creator.add(b"", b"empty").unwrap();
+            creator.add(b"two", b"2").unwrap();
+            creator.add(b"three", b"3").unwrap();
+            creator.add(b"two", b"22").unwrap();
+            creator.add(b"four", b"4").unwrap();
+            assert!(creator.remove(b"two", true).unwrap());
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        // The zeroed records are still in the file, but aren't records of
+        // the database any more, unlike the one with a genuinely empty key.
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.iter().len(), c.iter().count());
+        assert_eq!(c.keys().len(), c.keys().count());
+        assert_eq!(c.values().len(), c.values().count());
+        let keys: Vec<&[u8]> = c.keys().collect();
+        assert_eq!(keys, vec![&b"one"[..], b"", b"three", b"four"]);
+
+        // Several iterators can be used at once.
+        let pairs: Vec<_> = c.iter().zip(c.iter().skip(1)).collect();
+        assert_eq!(pairs.len(), 3);
+    }
+
+    #[test]
+    fn test_iter_from_cursor() {
+        use super::CdbCursor;
+
+        let path = Path::new("cursor.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..10 {
+                let key = format!("key{}", i);
+                creator.add(key.as_bytes(), b"val").unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let saved = {
+            let mut it = c.iter();
+            for _ in 0..4 {
+                it.next();
+            }
+            it.cursor().offset()
+        };
+
+        let cursor = CdbCursor::from_offset(saved);
+        let rest: Vec<&[u8]> = c.iter_from(cursor).unwrap().map(|(k, _)| k).collect();
+        assert_eq!(rest.len(), 6);
+        assert_eq!(rest[0], b"key4");
+        assert_eq!(c.iter_from(cursor).unwrap().len(), 6);
+
+        // The length is worked out from where the iterator has got to.
+        let mut it = c.iter_from(cursor).unwrap();
+        it.next();
+        it.next();
+        assert_eq!(it.len(), 4);
+        it.next();
+        assert_eq!(it.len(), 3);
+
+        // Cursors pointing into the middle of a record, into the header or
+        // past the end of the file are all rejected.
+        for &bad in &[saved + 1, 0, 100, u32::MAX] {
+            match c.iter_from(CdbCursor::from_offset(bad)) {
+                Err(ref e) => match *e.kind() {
+                    super::CdbErrorKind::InvalidCursor => {},
+                    ref k => panic!("Unexpected error kind: {:?}", k),
+                },
+                Ok(_) => panic!("Cursor at {} was accepted", bad),
+            }
+        }
+
+        // A cursor at the very end yields nothing.
+        let end = {
+            let mut it = c.iter();
+            while it.next().is_some() {}
+            it.cursor()
+        };
+        assert_eq!(c.iter_from(end).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_record_at() {
+        let path = Path::new("record_at.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..1000 {
+                let key = format!("key{}", i);
+                let val = format!("val{}", i);
+                creator.add(key.as_bytes(), val.as_bytes()).unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        assert_eq!(c.record_at(0), Some((&b"key0"[..], &b"val0"[..])));
+        assert_eq!(c.record_at(567), Some((&b"key567"[..], &b"val567"[..])));
+        assert_eq!(c.record_at(999), Some((&b"key999"[..], &b"val999"[..])));
+        assert_eq!(c.record_at(1000), None);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_sample() {
+        use rand::SeedableRng;
+        use rand::rngs::StdRng;
+
+        let path = Path::new("sample.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                creator.add(key.as_bytes(), b"val").unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut keys: Vec<&[u8]> = c.sample(10, &mut rng).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys.len(), 10);
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 10);
+
+        assert_eq!(c.sample(1000, &mut rng).len(), 100);
+    }
+
+    #[test]
+    fn test_close() {
+        // Whether the file at the given path is still mapped into memory.
+        fn is_mapped(name: &str) -> bool {
+            let mut maps = String::new();
+            File::open("/proc/self/maps").unwrap().read_to_string(&mut maps).unwrap();
+            maps.lines().any(|line| line.ends_with(name))
+        }
+
+        let path = Path::new("close_maps.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"foo", b"bar").unwrap();
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+        assert!(is_mapped("/close_maps.cdb"));
+        drop(c);
+        assert!(!is_mapped("/close_maps.cdb"));
+
+        for _ in 0..10 {
+            let c = Cdb::open(path).unwrap();
+            assert!(is_mapped("/close_maps.cdb"));
+            assert_eq!(c.cdb.cdb_fsize() as usize, c.mapping().unwrap().len());
+            c.close().unwrap();
+            assert!(!is_mapped("/close_maps.cdb"));
+        }
+
+        // A file that's too short to be a database is never mapped.
+        let short = Path::new("close_short.cdb");
+        let _rem_short = RemovingPath::new(short);
+        File::create(short).unwrap().write_all(b"short").unwrap();
+        assert!(Cdb::open(short).is_err());
+    }
+
+    #[test]
+    fn test_too_large() {
+        fn check_too_large<T: std::fmt::Debug>(res: super::CdbResult<T>) {
+            match res {
+                Err(ref e) => match *e.kind() {
+                    super::CdbErrorKind::TooLarge => {},
+                    ref k => panic!("Unexpected error kind: {:?}", k),
+                },
+                Ok(v) => panic!("Too-large input was accepted: {:?}", v),
+            }
+        }
+
+        // Reserve just over 4GiB of address space, which is never touched, to
+        // stand in for a huge key or value.
+        let huge_len = (u32::MAX as usize) + 2;
+        let mem = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                huge_len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert!(mem != libc::MAP_FAILED);
+        let huge = unsafe { std::slice::from_raw_parts(mem as *const u8, huge_len) };
+
+        let path = Path::new("too_large.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            check_too_large(creator.add(b"key", huge));
+            check_too_large(creator.add(huge, b"val"));
+            check_too_large(creator.put(b"key", huge, super::CdbPutMode::Replace));
+            check_too_large(creator.exists(huge));
+            check_too_large(creator.remove(huge, false));
+
+            creator.add(b"one", b"1").unwrap();
+
+            // Pretend that the file is nearly full: the record itself and
+            // its two hash table slots take 8 + 3 + 1 + 16 bytes.
+            creator.size = u32::MAX as u64 - 28;
+            check_too_large(creator.add(b"two", b"22"));
+            creator.add(b"two", b"2").unwrap();
+            check_too_large(creator.add(b"", b""));
+        });
+
+        let mut c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.find(b"two").unwrap(), b"2");
+
+        // Lookups of a huge key find nothing.
+        assert!(c.find(huge).is_none());
+        assert!(!c.exists(huge));
+        assert!(c.find_all(huge).next().is_none());
+        let mut f = File::open(path).unwrap();
+        assert!(super::seek(&mut f, huge).unwrap().is_none());
+
+        unsafe { libc::munmap(mem, huge_len) };
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow, clippy::borrow_deref_ref)]
     fn test_send() {
         use std::thread::spawn;
 
@@ -0,0 +1,298 @@
+/*!
+ * Metadata describing a database as a whole, such as who built it and from
+ * what.
+ *
+ * All of the metadata is stored in a single record, under a key starting
+ * with `RESERVED_PREFIX`.  Creators refuse to add any other key with that
+ * prefix, so the record can't clash with the rest of the database.  It is
+ * left out of iteration and of `Cdb::len()`, and can only be read back with
+ * `Cdb::metadata()`.
+ *
+ * When asked for any sidecar files, a creator also stores a random id for
+ * the database in a record of its own, which the sidecar files remember, so
+ * that they can't be mistaken for those of a rebuilt database.
+ *
+ * Only the records this crate writes itself are left out.  Other keys
+ * starting with `RESERVED_PREFIX`, which a database made by another tool may
+ * have, are iterated over and counted like any other.
+ *
+ * The value of the record is a sequence of entries, each being the length of
+ * the name, the name, the length of the value and the value, with lengths
+ * encoded as in the rest of the file.
+ */
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::str;
+use std::time::SystemTime;
+
+use libc::{self, c_uint};
+
+use super::{format, Cdb, CdbCreator, CdbError, CdbErrorKind, CdbResult};
+
+/// Keys starting with this are reserved for metadata.  A leading NUL byte
+/// keeps them out of the way of text keys.
+pub const RESERVED_PREFIX: &[u8] = b"\0tinycdb:";
+
+// The key of the metadata record.
+pub(crate) const METADATA_KEY: &[u8] = b"\0tinycdb:metadata";
+
+// The key of the record holding the id of the database.
+pub(crate) const ID_KEY: &[u8] = b"\0tinycdb:id";
+
+// The keys of the records this crate adds to a database itself, which aren't
+// part of its records.
+pub(crate) const INTERNAL_KEYS: &[&[u8]] = &[METADATA_KEY, ID_KEY];
+
+// Whether the given key is reserved, and so can't be added by a creator.
+#[inline]
+pub(crate) fn is_reserved(key: &[u8]) -> bool {
+    key.starts_with(RESERVED_PREFIX)
+}
+
+pub(crate) fn reserved_key_error() -> CdbError {
+    CdbError::new(
+        "Keys starting with the metadata prefix are reserved",
+        CdbErrorKind::IoError(io::Error::from(io::ErrorKind::InvalidInput)),
+    )
+}
+
+// Picks an id for a new database.  This only needs to tell databases apart,
+// not to be unpredictable, so the randomly seeded hasher of `HashMap` does.
+pub(crate) fn new_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
+}
+
+pub(crate) fn encode(entries: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in entries {
+        // Both were checked by `CdbCreator::set_metadata`.
+        buf.extend_from_slice(&format::pack(name.len() as u32));
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&format::pack(value.len() as u32));
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+fn decode(mut buf: &[u8]) -> Option<BTreeMap<&str, &[u8]>> {
+    // Splits `len` bytes off the front of `buf`.
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if buf.len() < len {
+            return None
+        }
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        Some(head)
+    }
+
+    let mut entries = BTreeMap::new();
+    while !buf.is_empty() {
+        let nlen = format::unpack(take(&mut buf, 4)?) as usize;
+        let name = str::from_utf8(take(&mut buf, nlen)?).ok()?;
+        let vlen = format::unpack(take(&mut buf, 4)?) as usize;
+        entries.insert(name, take(&mut buf, vlen)?);
+    }
+    Some(entries)
+}
+
+impl Cdb {
+    /**
+     * `metadata()` returns the metadata stored in the database with
+     * `CdbCreator::set_metadata`, by name.  A database without any has an
+     * empty map.  Fails if the metadata record is malformed.
+     */
+    pub fn metadata(&self) -> CdbResult<BTreeMap<&str, &[u8]>> {
+        let val = match self.find_all(METADATA_KEY).next() {
+            Some(val) => val,
+            None      => return Ok(BTreeMap::new()),
+        };
+
+        match decode(val) {
+            Some(entries) => Ok(entries),
+            None          => Err(CdbError::new(
+                "Invalid metadata record",
+                CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)),
+            )),
+        }
+    }
+
+    // Returns the id stored by the creator of this database, if it stored
+    // one.  Like `internal_offsets`, this ignores any filter or checksums,
+    // which are themselves checked against the id.
+    pub(crate) fn id(&self) -> Option<u64> {
+        let val = self.table_find_all(ID_KEY).next()?;
+        if val.len() != 8 {
+            return None
+        }
+        let mut b = [0u8; 8];
+        b.copy_from_slice(val);
+        Some(u64::from_le_bytes(b))
+    }
+
+    // Returns the offsets of the records under `INTERNAL_KEYS`.  This goes
+    // straight to the hash tables, since it's about how the file is laid out,
+    // and a filter or checksums shouldn't change the answer.
+    pub(crate) fn internal_offsets(&self) -> Vec<c_uint> {
+        let mut ret = Vec::new();
+        for key in INTERNAL_KEYS {
+            let mut found = self.table_find_all(key);
+            while found.next().is_some() {
+                ret.push(found.cdb.cdb_datapos() - (format::RECORD_HEADER_SIZE + key.len()) as c_uint);
+            }
+        }
+        ret
+    }
+}
+
+impl CdbCreator {
+    /**
+     * `set_metadata(name, value)` stores a piece of metadata about the
+     * database, such as when or from what it was built, to be read back with
+     * `Cdb::metadata()`.  Setting the same name again replaces the value.
+     * The metadata is written when the database is finished.
+     */
+    pub fn set_metadata(&mut self, name: &str, value: &[u8]) -> CdbResult<()> {
+        if name.len() > u32::MAX as usize || value.len() > u32::MAX as usize {
+            return Err(CdbError::new("Metadata is larger than 4GiB", CdbErrorKind::TooLarge));
+        }
+
+        self.metadata.insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    use libc::{c_uint, c_void};
+
+    use super::super::ffi;
+    use super::super::tests::RemovingPath;
+    use super::super::{BloomFilter, Cdb, CdbPutMode, OpenOptions, PreadCdb};
+    use super::{decode, encode, METADATA_KEY};
+
+    #[test]
+    fn test_metadata() {
+        let path = Path::new("metadata.cdb");
+        let _rem = RemovingPath::new(path);
+        let filter_path = BloomFilter::sidecar_path(path);
+        let _rem_filter = RemovingPath::new(&filter_path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.set_filter(&filter_path, 0.01).unwrap();
+            creator.set_metadata("built-by", b"someone").unwrap();
+            creator.add(b"one", b"1").unwrap();
+            creator.set_metadata("schema", b"1").unwrap();
+            creator.add(b"two", b"2").unwrap();
+            creator.set_metadata("schema", b"2").unwrap();
+
+            // Reserved keys are refused.
+            assert!(creator.add(METADATA_KEY, b"").is_err());
+            assert!(creator.add(b"\0tinycdb:other", b"").is_err());
+            assert!(creator.put(METADATA_KEY, b"", CdbPutMode::Replace).is_err());
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let meta = c.metadata().unwrap();
+        assert_eq!(meta.len(), 2);
+        assert_eq!(meta["built-by"], b"someone");
+        assert_eq!(meta["schema"], b"2");
+
+        // The metadata record isn't one of the records of the database.
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.stats().unwrap().records, 2);
+        assert_eq!(c.keys().collect::<Vec<_>>(), vec![b"one", b"two"]);
+        assert_eq!(c.iter().len(), 2);
+        assert!(c.record_at(2).is_none());
+        assert_eq!(c.prefix(b"").count(), 2);
+        let pread = PreadCdb::open(path).unwrap();
+        assert_eq!(pread.iter().count(), 2);
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::iter::ParallelIterator;
+            assert_eq!(c.par_iter().count(), 2);
+        }
+
+        // A cursor at the metadata record is at the end of the records.
+        let mut iter = c.iter();
+        iter.next();
+        iter.next();
+        let cursor = iter.cursor();
+        assert_eq!(c.iter_from(cursor).unwrap().count(), 0);
+
+        // The filter knows about the metadata record too.
+        let c = Cdb::open_with(path, OpenOptions::new().filter(&filter_path)).unwrap();
+        assert_eq!(c.metadata().unwrap()["schema"], b"2");
+
+        // A database without metadata has none.
+        let plain = Path::new("metadata_plain.cdb");
+        let _rem_plain = RemovingPath::new(plain);
+        let c = Cdb::new(plain, |creator| {
+            creator.add(b"one", b"1").unwrap();
+        }).unwrap();
+        assert!(c.metadata().unwrap().is_empty());
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn test_foreign_reserved_keys() {
+        let path = Path::new("metadata_foreign.cdb");
+        let _rem = RemovingPath::new(path);
+
+        // Written directly with TinyCDB, as another tool would, since our own
+        // creators refuse reserved keys.
+        let f = File::create(path).unwrap();
+        unsafe {
+            let mut cdbm: ffi::cdb_make = std::mem::zeroed();
+            assert_eq!(ffi::cdb_make_start(&mut cdbm, f.as_raw_fd()), 0);
+            for &(key, val) in &[(&b"\0tinycdb:other"[..], &b"x"[..]), (b"one", b"1")] {
+                let res = ffi::cdb_make_add(&mut cdbm, key.as_ptr() as *const c_void, key.len() as c_uint,
+                                            val.as_ptr() as *const c_void, val.len() as c_uint);
+                assert_eq!(res, 0);
+            }
+            assert_eq!(ffi::cdb_make_finish(&mut cdbm), 0);
+        }
+        drop(f);
+
+        // Only the records we write ourselves are hidden.
+        let c = Cdb::open(path).unwrap();
+        assert!(c.metadata().unwrap().is_empty());
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.iter().len(), 2);
+        assert_eq!(c.keys().collect::<Vec<_>>(), vec![&b"\0tinycdb:other"[..], &b"one"[..]]);
+        assert_eq!(c.values().len(), c.values().count());
+        assert_eq!(PreadCdb::open(path).unwrap().iter().count(), 2);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut entries = BTreeMap::new();
+        entries.insert("".to_owned(), b"empty name".to_vec());
+        entries.insert("codec".to_owned(), b"".to_vec());
+        let buf = encode(&entries);
+
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[""], b"empty name");
+        assert_eq!(decoded["codec"], b"");
+
+        assert!(decode(&buf[..buf.len() - 1]).is_none());
+        assert!(decode(&[1, 0, 0]).is_none());
+        assert!(decode(&[1, 0, 0, 0, 0xff, 0, 0, 0, 0]).is_none());
+    }
+}
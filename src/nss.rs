@@ -0,0 +1,444 @@
+/*!
+ * Typed access to the passwd, group and shadow databases used by TinyCDB's
+ * `nss_cdb` module.
+ *
+ * These use the same layout as `nss_cdb-Makefile`: every entry is stored
+ * under its name, with the whole line from `/etc/passwd` (or `/etc/group`,
+ * or `/etc/shadow`) as the value.  Passwd and group entries are also stored
+ * under `:` followed by their numeric id, with their name as the value, so
+ * that a lookup by id is a lookup of the name followed by a lookup by name.
+ *
+ * As in `nss_cdb`, entries that can't be parsed are treated as if they
+ * weren't there.  Entries must also be valid UTF-8.
+ */
+
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str;
+
+use libc::mode_t;
+
+use super::{Cdb, CdbCreator, CdbCursor, CdbError, CdbErrorKind, CdbResult, Records};
+
+/// An entry in one of the NSS databases.
+pub trait NssEntry: Sized {
+    /**
+     * Parses an entry from a line of the corresponding file in `/etc`,
+     * without the trailing newline.
+     */
+    fn parse(line: &str) -> Option<Self>;
+
+    /**
+     * Returns the name that the entry is stored under.
+     */
+    fn name(&self) -> &str;
+
+    /**
+     * Returns the numeric id that the entry is also stored under, if this
+     * kind of entry has one.
+     */
+    fn id(&self) -> Option<u32>;
+
+    /**
+     * The permissions that `NssDb::create` gives a new database of these
+     * entries.
+     */
+    const MODE: mode_t = 0o644;
+}
+
+/// An entry from the passwd database, as in `struct passwd`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Passwd {
+    /// The user's login name.
+    pub name: String,
+    /// The user's password, usually `x` when shadow passwords are used.
+    pub passwd: String,
+    /// The user's id.
+    pub uid: u32,
+    /// The id of the user's primary group.
+    pub gid: u32,
+    /// The user's real name, and other information.
+    pub gecos: String,
+    /// The user's home directory.
+    pub dir: String,
+    /// The user's login shell.
+    pub shell: String,
+}
+
+/// An entry from the group database, as in `struct group`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    /// The group's name.
+    pub name: String,
+    /// The group's password, usually `x`.
+    pub passwd: String,
+    /// The group's id.
+    pub gid: u32,
+    /// The login names of the group's members.
+    pub members: Vec<String>,
+}
+
+/// An entry from the shadow database, as in `struct spwd`.  Fields that are
+/// empty in the file are `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shadow {
+    /// The user's login name.
+    pub name: String,
+    /// The user's encrypted password.
+    pub passwd: String,
+    /// The date of the last password change, in days since the epoch.
+    pub last_change: Option<i64>,
+    /// The minimum password age, in days.
+    pub min: Option<i64>,
+    /// The maximum password age, in days.
+    pub max: Option<i64>,
+    /// The password warning period, in days.
+    pub warn: Option<i64>,
+    /// The password inactivity period, in days.
+    pub inactive: Option<i64>,
+    /// The date of expiration of the account, in days since the epoch.
+    pub expire: Option<i64>,
+    /// A reserved field.
+    pub flag: Option<u64>,
+}
+
+// Parses a numeric field, which, like with `strtoul`, must start with a
+// digit, but unlike it must not contain anything else.
+fn number<T: str::FromStr>(field: &str) -> Option<T> {
+    if !field.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+// Like `number`, but an empty field is allowed, and is `None`.
+fn maybe_number<T: str::FromStr>(field: &str) -> Option<Option<T>> {
+    match field {
+        "" => Some(None),
+        _  => number(field).map(Some),
+    }
+}
+
+impl NssEntry for Passwd {
+    fn parse(line: &str) -> Option<Passwd> {
+        let mut fields = line.splitn(7, ':');
+        let ret = Passwd {
+            name: fields.next()?.to_owned(),
+            passwd: fields.next()?.to_owned(),
+            uid: number(fields.next()?)?,
+            gid: number(fields.next()?)?,
+            gecos: fields.next()?.to_owned(),
+            dir: fields.next()?.to_owned(),
+            shell: fields.next()?.to_owned(),
+        };
+        if ret.name.is_empty() {
+            return None;
+        }
+        Some(ret)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.uid)
+    }
+}
+
+impl NssEntry for Group {
+    fn parse(line: &str) -> Option<Group> {
+        let mut fields = line.splitn(4, ':');
+        let ret = Group {
+            name: fields.next()?.to_owned(),
+            passwd: fields.next()?.to_owned(),
+            gid: number(fields.next()?)?,
+            members: fields.next()?
+                .split(',')
+                .filter(|m| !m.is_empty())
+                .map(|m| m.to_owned())
+                .collect(),
+        };
+        if ret.name.is_empty() {
+            return None;
+        }
+        Some(ret)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        Some(self.gid)
+    }
+}
+
+impl NssEntry for Shadow {
+    // Password hashes must only be readable by root, as for /etc/shadow.
+    const MODE: mode_t = 0o600;
+
+    fn parse(line: &str) -> Option<Shadow> {
+        let mut fields = line.splitn(9, ':');
+        let ret = Shadow {
+            name: fields.next()?.to_owned(),
+            passwd: fields.next()?.to_owned(),
+            last_change: maybe_number(fields.next()?)?,
+            min: maybe_number(fields.next()?)?,
+            max: maybe_number(fields.next()?)?,
+            warn: maybe_number(fields.next()?)?,
+            inactive: maybe_number(fields.next()?)?,
+            expire: maybe_number(fields.next()?)?,
+            flag: maybe_number(fields.next()?)?,
+        };
+        if ret.name.is_empty() {
+            return None;
+        }
+        Some(ret)
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn id(&self) -> Option<u32> {
+        None
+    }
+}
+
+/**
+ * `add_entries(creator, text)` adds all the entries in `text`, which is in
+ * the format of the corresponding file in `/etc`, to a database that is
+ * being created.  Comments and lines that can't be parsed are skipped.  The
+ * number of entries added is returned.
+ */
+pub fn add_entries<T: NssEntry>(creator: &mut CdbCreator, text: &str) -> CdbResult<usize> {
+    let mut count = 0;
+    for line in text.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let entry = match T::parse(line) {
+            Some(entry) => entry,
+            None        => continue,
+        };
+
+        creator.add(entry.name().as_bytes(), line.as_bytes())?;
+        if let Some(id) = entry.id() {
+            creator.add(format!(":{}", id).as_bytes(), entry.name().as_bytes())?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// An NSS database holding entries of type `T`.
+pub struct NssDb<T> {
+    db: Box<Cdb>,
+    _entries: PhantomData<T>,
+}
+
+impl<T: NssEntry> NssDb<T> {
+    /**
+     * `open(path)` opens the database at the given path.
+     */
+    pub fn open(path: &Path) -> CdbResult<NssDb<T>> {
+        Cdb::open(path).map(NssDb::from_cdb)
+    }
+
+    /**
+     * `from_cdb(db)` wraps an already-open database.
+     */
+    pub fn from_cdb(db: Box<Cdb>) -> NssDb<T> {
+        NssDb {
+            db,
+            _entries: PhantomData,
+        }
+    }
+
+    /**
+     * `create(path, text)` creates a new database at the given path with all
+     * the entries in `text`, with the permissions given by `NssEntry::MODE`.
+     * See `add_entries`.
+     */
+    pub fn create(path: &Path, text: &str) -> CdbResult<NssDb<T>> {
+        let mut res = Ok(0);
+        let db = Cdb::new_with_mode(path, T::MODE, |creator| {
+            res = add_entries::<T>(creator, text);
+        })?;
+        res?;
+        Ok(NssDb::from_cdb(db))
+    }
+
+    /**
+     * `by_name(name)` looks up the entry with the given name.
+     */
+    pub fn by_name(&self, name: &str) -> CdbResult<Option<T>> {
+        // Those would be the keys for ids.
+        if name.starts_with(':') {
+            return Ok(None);
+        }
+        Ok(self.get(name.as_bytes())?.and_then(T::parse))
+    }
+
+    /**
+     * `by_id(id)` looks up the entry with the given numeric id.  This is
+     * `getpwuid` for passwd entries and `getgrgid` for groups; shadow
+     * entries can't be looked up by id.
+     */
+    pub fn by_id(&self, id: u32) -> CdbResult<Option<T>> {
+        match self.get(format!(":{}", id).as_bytes())? {
+            Some(name) => Ok(self.get(name.as_bytes())?.and_then(T::parse)),
+            None       => Ok(None),
+        }
+    }
+
+    /**
+     * `iter()` returns an iterator over all the entries in the database.
+     */
+    pub fn iter(&self) -> NssIter<'_, T> {
+        NssIter {
+            records: Records::new(&self.db),
+            _entries: PhantomData,
+        }
+    }
+
+    /**
+     * `iter_from(cursor)` returns an iterator over the entries in the
+     * database, starting at a position previously returned by
+     * `NssIter::cursor()`.
+     */
+    pub fn iter_from(&self, cursor: CdbCursor) -> CdbResult<NssIter<'_, T>> {
+        if !self.db.is_record_start(cursor.offset)? {
+            return Err(CdbError::new(
+                "Cursor does not point at a record",
+                CdbErrorKind::InvalidCursor,
+            ));
+        }
+        Ok(NssIter {
+            records: Records::new_at(&self.db, cursor),
+            _entries: PhantomData,
+        })
+    }
+
+    // Returns the value stored under the given key, if it's valid UTF-8.
+    // Values shorter than 2 bytes can't be valid entries, and are ignored as
+    // in `nss_cdb`.
+    fn get(&self, key: &[u8]) -> CdbResult<Option<&str>> {
+        let val = self.db.locate(key)?.map(|(pos, len)| unsafe {
+            self.db.get_slice(pos, len)
+        });
+        Ok(val.filter(|v| v.len() >= 2).and_then(|v| str::from_utf8(v).ok()))
+    }
+}
+
+/// An iterator over the entries in an `NssDb`, created by `NssDb::iter()`.
+/// Records stored under ids are skipped.
+pub struct NssIter<'a, T> {
+    records: Records<'a>,
+    _entries: PhantomData<T>,
+}
+
+impl<'a, T> NssIter<'a, T> {
+    /**
+     * `cursor()` returns the position of the next entry, which can be passed
+     * to `NssDb::iter_from` to resume from there.
+     */
+    pub fn cursor(&self) -> CdbCursor {
+        self.records.cursor()
+    }
+}
+
+impl<'a, T: NssEntry> Iterator for NssIter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while self.records.advance() {
+            let key = self.records.key();
+            if key.len() < 2 || key[0] == b':' {
+                continue;
+            }
+            let entry = str::from_utf8(self.records.value()).ok().and_then(T::parse);
+            if entry.is_some() {
+                return entry;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use super::super::tests::RemovingPath;
+    use super::{Group, NssDb, NssEntry, Passwd, Shadow};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Group::parse("wheel:x:10:root,,alice"), Some(Group {
+            name: "wheel".to_owned(),
+            passwd: "x".to_owned(),
+            gid: 10,
+            members: vec!["root".to_owned(), "alice".to_owned()],
+        }));
+        assert_eq!(Shadow::parse("alice:$6$abc:19000:0:99999:7:::").map(|s| (s.max, s.expire, s.flag)),
+                   Some((Some(99999), None, None)));
+
+        assert_eq!(Passwd::parse(":x:0:0:root:/root:/bin/sh"), None);
+        assert_eq!(Passwd::parse("root:x:-1:0:root:/root:/bin/sh"), None);
+        assert_eq!(Passwd::parse("root:x:0:0:root:/root"), None);
+        assert_eq!(Shadow::parse("alice:*:1:2:3:4:5:6:x"), None);
+    }
+
+    #[test]
+    fn test_passwd() {
+        let path = Path::new("passwd.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let text = "# users\n\
+                    root:x:0:0:root:/root:/bin/bash\n\
+                    broken:x:zero:0::/:\n\
+                    alice:x:1000:1000:Alice,,,:/home/alice:/bin/zsh\n";
+        let db: NssDb<Passwd> = match NssDb::create(path, text) {
+            Ok(db) => db,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let alice = db.by_name("alice").unwrap().unwrap();
+        assert_eq!(alice.uid, 1000);
+        assert_eq!(alice.gecos, "Alice,,,");
+        assert_eq!(alice.shell, "/bin/zsh");
+        assert_eq!(db.by_id(1000).unwrap(), Some(alice));
+
+        assert_eq!(db.by_id(0).unwrap().unwrap().name, "root");
+        assert_eq!(db.by_id(1).unwrap(), None);
+        assert_eq!(db.by_name("broken").unwrap(), None);
+        assert_eq!(db.by_name(":0").unwrap(), None);
+
+        let names: Vec<String> = db.iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["root", "alice"]);
+
+        // Enumeration can be resumed.
+        let mut iter = db.iter();
+        iter.next();
+        let cursor = iter.cursor();
+        let names: Vec<String> = db.iter_from(cursor).unwrap().map(|p| p.name).collect();
+        assert_eq!(names, vec!["alice"]);
+    }
+
+    #[test]
+    fn test_shadow() {
+        let path = Path::new("shadow.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let db: NssDb<Shadow> = NssDb::create(path, "alice:$6$abc:19000:0:99999:7:::\n").unwrap();
+        assert_eq!(db.by_name("alice").unwrap().unwrap().passwd, "$6$abc");
+
+        // Only the owner can read the password hashes.
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,253 @@
+/*!
+ * Options controlling how a database is mapped into memory when opened.
+ */
+
+use std::path::{Path, PathBuf};
+
+use libc::{self, c_void};
+
+use super::{CdbError, CdbResult};
+#[cfg(feature = "signing")]
+use super::VerifyingKey;
+
+/// The access pattern to advise the kernel of for a database's mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    /// No particular advice; the kernel's default read-ahead is used.
+    Normal,
+
+    /// Records will be accessed in random order, as is the case for lookups.
+    /// This disables read-ahead, so that a lookup only reads in the pages it
+    /// actually touches.
+    Random,
+
+    /// Records will be accessed in order, as is the case when iterating over
+    /// the whole database.  This enables aggressive read-ahead.
+    Sequential,
+}
+
+/// Options for opening a database with `Cdb::open_with`.
+///
+/// This follows the builder style of `std::fs::OpenOptions`:
+///
+/// ```no_run
+/// use std::path::Path;
+/// use tinycdb::{Advice, Cdb, OpenOptions};
+///
+/// let db = Cdb::open_with(
+///     Path::new("test.cdb"),
+///     OpenOptions::new().advice(Advice::Random).populate(true),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    advice: Advice,
+    populate: bool,
+    lock: bool,
+    huge_pages: bool,
+    filter: Option<PathBuf>,
+    index: Option<PathBuf>,
+    #[cfg(feature = "checksum")]
+    checksums: Option<PathBuf>,
+    #[cfg(feature = "checksum")]
+    checked: bool,
+    #[cfg(feature = "signing")]
+    public_keys: Option<Vec<VerifyingKey>>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
+    }
+}
+
+impl OpenOptions {
+    /**
+     * Creates a new set of options, with the same behaviour as `Cdb::open`.
+     */
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            advice: Advice::Normal,
+            populate: false,
+            lock: false,
+            huge_pages: false,
+            filter: None,
+            index: None,
+            #[cfg(feature = "checksum")]
+            checksums: None,
+            #[cfg(feature = "checksum")]
+            checked: false,
+            #[cfg(feature = "signing")]
+            public_keys: None,
+        }
+    }
+
+    /**
+     * Sets the access pattern to advise the kernel of.
+     */
+    pub fn advice(&mut self, advice: Advice) -> &mut OpenOptions {
+        self.advice = advice;
+        self
+    }
+
+    /**
+     * If set, the whole file is read into memory when the database is opened,
+     * similarly to mapping it with `MAP_POPULATE`.  Opening then takes longer,
+     * but the first lookups don't need to wait for the disk.
+     */
+    pub fn populate(&mut self, populate: bool) -> &mut OpenOptions {
+        self.populate = populate;
+        self
+    }
+
+    /**
+     * If set, the whole file is locked into memory with `mlock`, so that it
+     * can't be paged out.  This usually requires raising `RLIMIT_MEMLOCK` or
+     * having the `CAP_IPC_LOCK` capability, and opening fails otherwise.
+     */
+    pub fn lock(&mut self, lock: bool) -> &mut OpenOptions {
+        self.lock = lock;
+        self
+    }
+
+    /**
+     * If set, the kernel is asked to back the mapping with huge pages.  Not
+     * all kernels and filesystems support this for file mappings, so this is
+     * only a hint and failures are ignored.  Does nothing on systems other
+     * than Linux.
+     */
+    pub fn huge_pages(&mut self, huge_pages: bool) -> &mut OpenOptions {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /**
+     * If set, the Bloom filter at the given path is loaded along with the
+     * database, and consulted before every lookup.  Opening fails if the
+     * filter can't be read, or was built for a different database.  See
+     * `CdbCreator::set_filter`.
+     */
+    pub fn filter(&mut self, path: &Path) -> &mut OpenOptions {
+        self.filter = Some(path.to_path_buf());
+        self
+    }
+
+    /**
+     * If set, the key index at the given path is loaded along with the
+     * database, and used by `Cdb::prefix` and `Cdb::range`.  Opening fails if
+     * the index can't be read, or was built for a different database.  See
+     * `CdbCreator::set_index`.
+     */
+    pub fn index(&mut self, path: &Path) -> &mut OpenOptions {
+        self.index = Some(path.to_path_buf());
+        self
+    }
+
+    /**
+     * If set, the checksums at the given path are loaded along with the
+     * database, for `Cdb::verify_checksum` and checked lookups.  Opening
+     * fails if they can't be read, or were computed for a different database.
+     * See `CdbCreator::set_checksums`.  Available with the `checksum`
+     * feature.
+     */
+    #[cfg(feature = "checksum")]
+    pub fn checksums(&mut self, path: &Path) -> &mut OpenOptions {
+        self.checksums = Some(path.to_path_buf());
+        self
+    }
+
+    /**
+     * If set, every record found by a lookup is checked against its checksum
+     * before it is returned.  All lookups act as if a corrupted record wasn't
+     * there, going on to the next record with the same key, if there is one,
+     * so a key whose records are all corrupted isn't found.  This needs the
+     * checksums to be loaded, with `checksums()` or by `Cdb::new`, and
+     * opening fails otherwise.  Available with the `checksum` feature.
+     */
+    #[cfg(feature = "checksum")]
+    pub fn checked(&mut self, checked: bool) -> &mut OpenOptions {
+        self.checked = checked;
+        self
+    }
+
+    /**
+     * If set, opening fails unless the database was signed by one of the
+     * given keys, with `CdbCreator::set_signing_key`.  The whole file is read
+     * to check the signature.  Available with the `signing` feature.
+     */
+    #[cfg(feature = "signing")]
+    pub fn signed_by(&mut self, public_keys: &[VerifyingKey]) -> &mut OpenOptions {
+        self.public_keys = Some(public_keys.to_vec());
+        self
+    }
+
+    pub(crate) fn filter_path(&self) -> Option<&Path> {
+        self.filter.as_deref()
+    }
+
+    pub(crate) fn index_path(&self) -> Option<&Path> {
+        self.index.as_deref()
+    }
+
+    #[cfg(feature = "checksum")]
+    pub(crate) fn checksums_path(&self) -> Option<&Path> {
+        self.checksums.as_deref()
+    }
+
+    #[cfg(feature = "checksum")]
+    pub(crate) fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    #[cfg(feature = "signing")]
+    pub(crate) fn public_keys(&self) -> Option<&[VerifyingKey]> {
+        self.public_keys.as_deref()
+    }
+
+    // Applies these options to the mapping of an opened database.
+    pub(crate) fn apply(&self, mem: &[u8]) -> CdbResult<()> {
+        let addr = mem.as_ptr() as *mut c_void;
+        let len = mem.len();
+
+        let advice = match self.advice {
+            Advice::Normal     => libc::MADV_NORMAL,
+            Advice::Random     => libc::MADV_RANDOM,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+        };
+        if unsafe { libc::madvise(addr, len, advice) } < 0 {
+            return Err(CdbError::new_from_errno("Error advising kernel of access pattern"));
+        }
+
+        if self.huge_pages {
+            advise_huge_pages(addr, len);
+        }
+
+        if self.lock {
+            // This also faults in every page.
+            if unsafe { libc::mlock(addr, len) } < 0 {
+                return Err(CdbError::new_from_errno("Error locking database into memory"));
+            }
+        } else if self.populate {
+            unsafe { libc::madvise(addr, len, libc::MADV_WILLNEED) };
+
+            // The advice above is asynchronous, so touch every page to make
+            // sure that they're all resident once we return.
+            let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            for i in (0..len).step_by(page) {
+                unsafe { std::ptr::read_volatile(&mem[i]) };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Only Linux has transparent huge pages to ask for, so elsewhere this does
+// nothing.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(addr: *mut c_void, len: usize) {
+    unsafe { libc::madvise(addr, len, libc::MADV_HUGEPAGE) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_addr: *mut c_void, _len: usize) {}
@@ -0,0 +1,86 @@
+/*!
+ * Parallel iteration over a database, available with the `rayon` feature.
+ *
+ * The data section is split into chunks at record boundaries, which are
+ * found through the record positions listed in the hash tables, and the
+ * chunks are then walked concurrently.
+ */
+
+use libc::c_uint;
+use rayon::prelude::*;
+
+use super::{Cdb, CdbIterator, Records};
+
+// How many chunks to make per thread, so that threads that finish early can
+// pick up more work.
+const CHUNKS_PER_THREAD: usize = 4;
+
+impl Cdb {
+    /**
+     * `par_iter()` returns a parallel iterator over all the records in the
+     * database.  Records are produced in no particular order.
+     */
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (&[u8], &[u8])> + '_ {
+        let mut offsets: Vec<c_uint> = self.record_offsets().collect();
+        offsets.sort_unstable();
+
+        let chunks = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+        let per_chunk = std::cmp::max(1, offsets.len().div_ceil(chunks));
+
+        // The first chunk always starts at the beginning of the data section,
+        // and the last one runs until its end, so that records that aren't
+        // in the hash tables are still visited.
+        let mut ranges = Vec::with_capacity(chunks);
+        let mut start = 2048;
+        for (i, group) in offsets.chunks(per_chunk).enumerate() {
+            let end = match offsets.get((i + 1) * per_chunk) {
+                Some(&next) => next,
+                None        => c_uint::MAX,
+            };
+            ranges.push((start, end, group.len()));
+            start = end;
+        }
+
+        ranges.into_par_iter().flat_map_iter(move |(start, end, count)| {
+            CdbIterator { records: Records::new_range(self, start, end, count) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use rayon::prelude::*;
+
+    use super::super::Cdb;
+    use super::super::tests::RemovingPath;
+
+    #[test]
+    fn test_par_iter() {
+        let path = Path::new("par_iter.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            for i in 0..10000 {
+                let key = format!("key{}", i);
+                let val = format!("val{}", i);
+                creator.add(key.as_bytes(), val.as_bytes()).unwrap();
+            }
+        });
+
+        let c = match res {
+            Ok(c) => c,
+            Err(why) => panic!("Could not create: {:?}", why),
+        };
+
+        let mut par: Vec<(&[u8], &[u8])> = c.par_iter().collect();
+        par.sort();
+
+        let mut seq: Vec<(&[u8], &[u8])> = c.keys().zip(c.values()).collect();
+        seq.sort();
+
+        assert_eq!(par.len(), 10000);
+        assert_eq!(par, seq);
+    }
+}
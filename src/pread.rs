@@ -0,0 +1,380 @@
+/*!
+ * Reading databases with positioned reads instead of a memory mapping.
+ *
+ * A memory-mapped database is the fastest way to do lookups, but it is a poor
+ * fit for some filesystems: network mounts may not support `mmap` reliably,
+ * and if a mapped file is truncated underneath us, touching the missing pages
+ * kills the process with `SIGBUS`.  `PreadCdb` instead reads everything it
+ * needs with `pread`, so the same situation results in a `CdbError`.
+ */
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use libc::{self, c_uint};
+
+use super::{format, metadata, CdbError, CdbErrorKind, CdbResult, CdbValueReader, ReadAt};
+
+// Errors about malformed files are reported the same way TinyCDB does.
+fn protocol_error() -> CdbError {
+    CdbError::new(
+        "Invalid database format",
+        CdbErrorKind::IoError(io::Error::from_raw_os_error(libc::EPROTO)),
+    )
+}
+
+const SLOT_SIZE: c_uint = format::SLOT_SIZE as c_uint;
+
+fn read_error(e: io::Error) -> CdbError {
+    CdbError::new("Error reading from file", CdbErrorKind::IoError(e))
+}
+
+/// The `PreadCdb` struct represents a database that is read with positioned
+/// reads.  It offers the same lookups as `Cdb`, but returns owned values.
+pub struct PreadCdb {
+    file: File,
+    size: c_uint,
+    dend: c_uint,
+}
+
+impl PreadCdb {
+    /**
+     * `open(path)` will open the CDB database at the given file path,
+     * returning either the `PreadCdb` struct or an error indicating why the
+     * database could not be opened.
+     */
+    pub fn open(path: &Path) -> CdbResult<PreadCdb> {
+        match File::open(path) {
+            Ok(f) => PreadCdb::from_file(f),
+            Err(e) => Err(CdbError::new("Error opening file", CdbErrorKind::IoError(e))),
+        }
+    }
+
+    /**
+     * `from_file(file)` reads the CDB database from an already-open file,
+     * taking ownership of it.
+     */
+    pub fn from_file(file: File) -> CdbResult<PreadCdb> {
+        let size = match file.metadata() {
+            Ok(m) => std::cmp::min(m.len(), c_uint::MAX as u64) as c_uint,
+            Err(e) => return Err(CdbError::new("Error getting file size", CdbErrorKind::IoError(e))),
+        };
+
+        // Same sanity check as `cdb_init`: at least the table of contents
+        // should be here.
+        if size < 2048 {
+            return Err(protocol_error());
+        }
+
+        let mut ret = PreadCdb {
+            file,
+            size,
+            dend: 0,
+        };
+
+        // The data section ends where the first hash table starts.
+        let dend = ret.read_u32(0)?;
+        ret.dend = if dend < 2048 {
+            2048
+        } else if dend >= size {
+            size
+        } else {
+            dend
+        };
+
+        Ok(ret)
+    }
+
+    fn read_exact(&self, buf: &mut [u8], pos: c_uint) -> CdbResult<()> {
+        self.read_at(buf, pos).map_err(read_error)
+    }
+
+    fn read_u32(&self, pos: c_uint) -> CdbResult<u32> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf, pos)?;
+        Ok(format::unpack(&buf))
+    }
+
+    fn read_pair(&self, pos: c_uint) -> CdbResult<(u32, u32)> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf, pos)?;
+        Ok((format::unpack(&buf), format::unpack(&buf[4..])))
+    }
+
+    fn read_vec(&self, pos: c_uint, len: c_uint) -> CdbResult<Vec<u8>> {
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf, pos)?;
+        Ok(buf)
+    }
+
+    // Looks up every record for the given key, returning the position and
+    // length of their values.  This mirrors `cdb_findinit`/`cdb_findnext`.
+    fn locate_all(&self, key: &[u8]) -> CdbResult<Vec<(c_uint, c_uint)>> {
+        let mut ret = Vec::new();
+        if key.len() >= self.dend as usize {
+            return Ok(ret);
+        }
+
+        let klen = key.len() as c_uint;
+        let hash = format::hash(key);
+        let (htab, n) = self.read_pair((format::table(hash) * format::TOC_ENTRY_SIZE) as c_uint)?;
+        if n == 0 {
+            return Ok(ret);
+        }
+        if n > self.size / SLOT_SIZE || htab < self.dend || htab > self.size || n * SLOT_SIZE > self.size - htab {
+            return Err(protocol_error());
+        }
+
+        let mut slot = format::slot(hash, n);
+        for _ in 0..n {
+            let (hval, pos) = self.read_pair(htab + slot * SLOT_SIZE)?;
+            if pos == 0 {
+                break;
+            }
+            slot = (slot + 1) % n;
+
+            if hval != hash {
+                continue;
+            }
+            if pos > self.size - 8 {
+                return Err(protocol_error());
+            }
+            let (rklen, vlen) = self.read_pair(pos)?;
+            if rklen != klen {
+                continue;
+            }
+            if self.size - klen < pos + 8 {
+                return Err(protocol_error());
+            }
+            if self.read_vec(pos + 8, klen)? != key {
+                continue;
+            }
+            if self.size < vlen || self.size - vlen < pos + 8 + klen {
+                return Err(protocol_error());
+            }
+            ret.push((pos + 8 + klen, vlen));
+        }
+
+        Ok(ret)
+    }
+
+    /**
+     * `find(key)` searches the database for the given key, and, if it's found,
+     * will return the associated value.  Note that, since it is possible to
+     * have multiple records with the same key, `find` will only return the
+     * value of the first key.
+     */
+    pub fn find(&self, key: &[u8]) -> CdbResult<Option<Vec<u8>>> {
+        match self.locate_all(key)?.first() {
+            Some(&(pos, len)) => self.read_vec(pos, len).map(Some),
+            None              => Ok(None),
+        }
+    }
+
+    /**
+     * `find_all(key)` returns all the values stored under the given key, in
+     * the order in which they were added.
+     */
+    pub fn find_all(&self, key: &[u8]) -> CdbResult<Vec<Vec<u8>>> {
+        self.locate_all(key)?
+            .into_iter()
+            .map(|(pos, len)| self.read_vec(pos, len))
+            .collect()
+    }
+
+    /**
+     * `find_reader(key)` searches the database for the given key, and, if
+     * it's found, returns a reader over the associated value.  See
+     * `Cdb::find_reader`.
+     */
+    pub fn find_reader(&self, key: &[u8]) -> CdbResult<Option<CdbValueReader<'_>>> {
+        let ret = self.locate_all(key)?.first().map(|&(pos, len)| {
+            CdbValueReader::new(self, pos, len)
+        });
+        Ok(ret)
+    }
+
+    /**
+     * `exists(key)` returns whether the key exists in the database.
+     */
+    pub fn exists(&self, key: &[u8]) -> CdbResult<bool> {
+        Ok(!self.locate_all(key)?.is_empty())
+    }
+
+    /**
+     * `iter()` returns an iterator over all the records in the database.
+     */
+    pub fn iter(&self) -> PreadIterator<'_> {
+        PreadIterator {
+            underlying: self,
+            pos: 2048,
+            failed: false,
+        }
+    }
+}
+
+impl ReadAt for PreadCdb {
+    fn read_at(&self, buf: &mut [u8], pos: c_uint) -> io::Result<()> {
+        // A short read means that the file was truncated.
+        self.file.read_exact_at(buf, pos as u64)
+    }
+}
+
+/// A `PreadIterator` iterates over all the records in a `PreadCdb`.  If an
+/// error is encountered, it is returned and the iteration stops.
+pub struct PreadIterator<'a> {
+    underlying: &'a PreadCdb,
+    pos: c_uint,
+    failed: bool,
+}
+
+impl<'a> PreadIterator<'a> {
+    // Mirrors `cdb_seqnext`.
+    fn read_next(&mut self) -> CdbResult<Option<(Vec<u8>, Vec<u8>)>> {
+        let dend = self.underlying.dend;
+        if self.pos > dend - 8 {
+            return Ok(None);
+        }
+
+        let (klen, vlen) = self.underlying.read_pair(self.pos)?;
+        let pos = self.pos + 8;
+        let avail = dend - pos;
+        if klen > avail || vlen > avail - klen {
+            return Err(protocol_error());
+        }
+
+        let key = self.underlying.read_vec(pos, klen)?;
+        let val = self.underlying.read_vec(pos + klen, vlen)?;
+        self.pos = pos + klen + vlen;
+        Ok(Some((key, val)))
+    }
+
+    // Skips the records that `Cdb::iter` does too: those this crate adds
+    // itself, and the remains of records removed with `zero` set, which have
+    // an empty key and aren't in the hash tables any more.
+    fn read_visible(&mut self) -> CdbResult<Option<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let start = self.pos;
+            let (key, val) = match self.read_next()? {
+                Some(rec) => rec,
+                None      => return Ok(None),
+            };
+
+            if metadata::INTERNAL_KEYS.contains(&&key[..]) {
+                continue;
+            }
+            if key.is_empty() && !self.underlying.locate_all(b"")?.iter().any(|&(pos, _)| pos == start + 8) {
+                continue;
+            }
+            return Ok(Some((key, val)));
+        }
+    }
+}
+
+impl<'a> Iterator for PreadIterator<'a> {
+    type Item = CdbResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<CdbResult<(Vec<u8>, Vec<u8>)>> {
+        if self.failed {
+            return None;
+        }
+
+        match self.read_visible() {
+            Ok(rec) => rec.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use std::path::Path;
+
+    use super::super::Cdb;
+    use super::super::tests::RemovingPath;
+    use super::PreadCdb;
+
+    #[test]
+    fn test_pread() {
+        let path = Path::new("pread.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"one", b"Hello").unwrap();
+            creator.add(b"two", b"Goodbye").unwrap();
+            creator.add(b"gone", b"Zeroed").unwrap();
+            creator.add(b"one", b"Again").unwrap();
+            creator.add(b"", b"Empty").unwrap();
+            creator.add(b"last", b"").unwrap();
+            assert!(creator.remove(b"gone", true).unwrap());
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+
+        let c = match PreadCdb::open(path) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not open: {:?}", why),
+        };
+
+        assert_eq!(c.find(b"one").unwrap(), Some(b"Hello".to_vec()));
+        assert_eq!(c.find(b"bad").unwrap(), None);
+        assert!(c.exists(b"two").unwrap());
+        assert_eq!(c.find_all(b"one").unwrap(), vec![b"Hello".to_vec(), b"Again".to_vec()]);
+
+        let mut val = Vec::new();
+        c.find_reader(b"two").unwrap().unwrap().read_to_end(&mut val).unwrap();
+        assert_eq!(val, b"Goodbye");
+
+        let recs: Vec<(Vec<u8>, Vec<u8>)> = c.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(recs, vec![
+            (b"one".to_vec(), b"Hello".to_vec()),
+            (b"two".to_vec(), b"Goodbye".to_vec()),
+            (b"one".to_vec(), b"Again".to_vec()),
+            (b"".to_vec(), b"Empty".to_vec()),
+            (b"last".to_vec(), b"".to_vec()),
+        ]);
+
+        // The zeroed record is left out, as it is by `Cdb::iter`.
+        let mapped: Vec<(Vec<u8>, Vec<u8>)> = Cdb::open(path).unwrap().iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect();
+        assert_eq!(recs, mapped);
+        assert_eq!(c.find(b"gone").unwrap(), None);
+    }
+
+    #[test]
+    fn test_pread_truncated() {
+        let path = Path::new("pread_truncated.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"one", b"Hello").unwrap();
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+
+        let c = match PreadCdb::open(path) {
+            Ok(c) => c,
+            Err(why) => panic!("Could not open: {:?}", why),
+        };
+
+        // Truncate the file to just its table of contents, as if it was being
+        // replaced underneath us.
+        let f = OpenOptions::new().write(true).open(path).unwrap();
+        f.set_len(2048).unwrap();
+
+        assert!(c.find(b"one").is_err());
+        let recs: Vec<_> = c.iter().collect();
+        assert_eq!(recs.len(), 1);
+        assert!(recs[0].is_err());
+    }
+}
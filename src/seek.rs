@@ -0,0 +1,134 @@
+/*!
+ * One-shot lookups with plain reads, using TinyCDB's old `cdb_seek`
+ * interface.
+ *
+ * `seek` neither maps the file nor keeps any state besides the file's
+ * position, which makes it a good fit for processes that look up a single
+ * key and exit.  Every step of the lookup is a separate `read`, so for more
+ * than a handful of lookups `Cdb` or `PreadCdb` are much faster.
+ */
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_uint, c_void};
+
+use super::{c_len, CdbError, CdbResult};
+
+/**
+ * `seek(file, key)` looks up the given key in the database stored in
+ * `file`.  If it's found, the file is left positioned at the start of the
+ * value of the first record with that key, and a reader over that value is
+ * returned.
+ */
+pub fn seek<'a>(file: &'a mut File, key: &[u8]) -> CdbResult<Option<SeekReader<'a>>> {
+    // A key that's too long can't be in the database.
+    let klen = match c_len(key) {
+        Some(klen) => klen,
+        None       => return Ok(None),
+    };
+
+    let mut len: c_uint = 0;
+    let res = unsafe {
+        ffi::cdb_seek(
+            file.as_raw_fd(),
+            key.as_ptr() as *const c_void,
+            klen,
+            &mut len,
+        )
+    };
+    match res {
+        x if x < 0 => Err(CdbError::new_from_errno("Error seeking to key")),
+        0          => Ok(None),
+        _          => Ok(Some(SeekReader { file, remaining: len })),
+    }
+}
+
+/// A reader over a value found by `seek()`.  It reads from the current
+/// position of the file, so the file must not be read from or seeked
+/// elsewhere in the meantime.
+pub struct SeekReader<'a> {
+    file: &'a mut File,
+    remaining: c_uint,
+}
+
+impl<'a> SeekReader<'a> {
+    /**
+     * Returns the number of bytes of the value that haven't been read yet.
+     */
+    pub fn len(&self) -> u64 {
+        self.remaining as u64
+    }
+
+    /**
+     * Returns whether the whole value has been read.
+     */
+    pub fn is_empty(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+impl<'a> Read for SeekReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len() as u64, std::cmp::min(self.len(), c_int::MAX as u64)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // Reads exactly `n` bytes, retrying on `EINTR` and failing with
+        // `EIO` if the file ends early.
+        let res = unsafe {
+            ffi::cdb_bread(self.file.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, n as c_int)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.remaining -= n as c_uint;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+
+    use super::super::Cdb;
+    use super::super::tests::RemovingPath;
+    use super::seek;
+
+    #[test]
+    fn test_seek() {
+        let path = Path::new("seek.cdb");
+        let _rem = RemovingPath::new(path);
+
+        let res = Cdb::new(path, |creator| {
+            creator.add(b"one", b"Hello").unwrap();
+            creator.add(b"two", b"Goodbye").unwrap();
+            creator.add(b"one", b"Again").unwrap();
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+
+        let mut f = File::open(path).unwrap();
+        let mut val = Vec::new();
+        {
+            let mut reader = seek(&mut f, b"one").unwrap().unwrap();
+            assert_eq!(reader.len(), 5);
+            reader.read_to_end(&mut val).unwrap();
+            assert!(reader.is_empty());
+        }
+        assert_eq!(val, b"Hello");
+
+        // The same file can be used for several lookups.
+        assert!(seek(&mut f, b"three").unwrap().is_none());
+
+        let mut val = [0u8; 3];
+        seek(&mut f, b"two").unwrap().unwrap().read_exact(&mut val).unwrap();
+        assert_eq!(&val, b"Goo");
+    }
+}
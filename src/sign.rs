@@ -0,0 +1,180 @@
+/*!
+ * Signing databases with Ed25519, available with the `signing` feature.
+ *
+ * A database is signed by appending a trailer to the finished file: the
+ * signature of everything before it, followed by `MAGIC`.  Readers only
+ * follow the positions in the table of contents and the hash tables, so
+ * they never look at the trailer, and signed files can be used by any CDB
+ * reader.  `Cdb::open_verified` refuses files without a trailer, or whose
+ * signature wasn't made by one of the trusted keys.
+ */
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, Signer, SIGNATURE_LENGTH};
+use libc::{self, c_void};
+
+use super::{format, Cdb, CdbCreator, CdbError, CdbErrorKind, CdbResult, OpenOptions};
+use super::{SigningKey, VerifyingKey};
+
+const MAGIC: &[u8; 8] = b"TCDBSIG1";
+
+const TRAILER_SIZE: usize = SIGNATURE_LENGTH + 8;
+
+fn signature_error(msg: &'static str) -> CdbError {
+    CdbError::new(msg, CdbErrorKind::InvalidSignature)
+}
+
+impl Cdb {
+    /**
+     * `open_verified(path, public_keys)` opens the database at the given
+     * path like `open`, but only if it was signed by one of the given keys.
+     * Fails with `CdbErrorKind::InvalidSignature` otherwise.  The whole file
+     * is read to check the signature.
+     */
+    pub fn open_verified(path: &Path, public_keys: &[VerifyingKey]) -> CdbResult<Box<Cdb>> {
+        Cdb::open_with(path, OpenOptions::new().signed_by(public_keys))
+    }
+
+    // Checks the signature in the trailer against the given keys.
+    pub(crate) fn verify_signature(&self, public_keys: &[VerifyingKey]) -> CdbResult<()> {
+        let mem = self.mapping()?;
+        let (signed, trailer) = match mem.len().checked_sub(TRAILER_SIZE) {
+            Some(len) if len >= format::TOC_SIZE => mem.split_at(len),
+            _ => return Err(signature_error("Database is not signed")),
+        };
+        if &trailer[SIGNATURE_LENGTH..] != MAGIC {
+            return Err(signature_error("Database is not signed"));
+        }
+
+        let signature = match trailer[..SIGNATURE_LENGTH].try_into() {
+            Ok(bytes) => Signature::from_bytes(bytes),
+            Err(_)    => return Err(signature_error("Database is not signed")),
+        };
+        if public_keys.iter().any(|key| key.verify_strict(signed, &signature).is_ok()) {
+            Ok(())
+        } else {
+            Err(signature_error("Database is not signed by a trusted key"))
+        }
+    }
+}
+
+impl CdbCreator {
+    /**
+     * `set_signing_key(key)` makes the creator sign the database with the
+     * given key once it is finished, so that it can be opened with
+     * `Cdb::open_verified`.  As with `set_index`, a creator made with
+     * `from_file()` or `from_fd()` must have been given a readable file.
+     */
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        self.signing_key = Some(key);
+    }
+
+    // Appends the signature trailer to the finished database, if a key was
+    // given.
+    pub(crate) fn sign(&mut self) -> CdbResult<()> {
+        let key = match self.signing_key.take() {
+            Some(key) => key,
+            None      => return Ok(()),
+        };
+
+        let db = self.read_back()?;
+        let mem = db.mapping()?;
+        let mut trailer = key.sign(mem).to_bytes().to_vec();
+        trailer.extend_from_slice(MAGIC);
+
+        let res = unsafe {
+            libc::pwrite(
+                self.fd,
+                trailer.as_ptr() as *const c_void,
+                trailer.len(),
+                mem.len() as libc::off_t,
+            )
+        };
+        if res != trailer.len() as isize {
+            return Err(CdbError::new_from_errno("Error writing signature"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use std::fs::OpenOptions as FileOptions;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use self::rand::rngs::OsRng;
+
+    use super::super::tests::RemovingPath;
+    use super::super::{Cdb, CdbErrorKind, CdbResult, KeyIndex, OpenOptions, PreadCdb};
+    use super::super::{SigningKey, VerifyingKey};
+
+    fn is_invalid<T>(res: CdbResult<T>) -> bool {
+        match res {
+            Err(ref e) => matches!(*e.kind(), CdbErrorKind::InvalidSignature),
+            Ok(_)      => false,
+        }
+    }
+
+    fn create(path: &Path, key: Option<&SigningKey>) {
+        let index_path = KeyIndex::sidecar_path(path);
+        let res = Cdb::new(path, |creator| {
+            if let Some(key) = key {
+                creator.set_signing_key(key.clone());
+            }
+            creator.set_index(&index_path);
+            creator.add(b"one", b"Hello").unwrap();
+            creator.add(b"two", b"Goodbye").unwrap();
+        });
+        if let Err(why) = res {
+            panic!("Could not create: {:?}", why);
+        }
+    }
+
+    #[test]
+    fn test_signing() {
+        let trusted = SigningKey::generate(&mut OsRng);
+        let other = SigningKey::generate(&mut OsRng);
+        let keys: Vec<VerifyingKey> = vec![other.verifying_key(), trusted.verifying_key()];
+
+        let path = Path::new("signed.cdb");
+        let _rem = RemovingPath::new(path);
+        let _rem_index = RemovingPath::new(&KeyIndex::sidecar_path(path));
+        create(path, Some(&trusted));
+
+        let mut c = Cdb::open_verified(path, &keys).unwrap();
+        assert_eq!(c.find(b"one").unwrap(), b"Hello");
+        assert!(is_invalid(Cdb::open_verified(path, &[other.verifying_key()])));
+
+        // Readers that don't check the signature don't notice it, and the
+        // sidecar files were built for the signed file.
+        let c = Cdb::open_with(path, OpenOptions::new().index(&KeyIndex::sidecar_path(path))).unwrap();
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.iter().count(), 2);
+        assert_eq!(c.prefix(b"t").count(), 1);
+        assert_eq!(PreadCdb::open(path).unwrap().find(b"two").unwrap().unwrap(), b"Goodbye");
+
+        // Any change to the file is caught.
+        let f = FileOptions::new().write(true).open(path).unwrap();
+        f.write_at(b"J", 2048 + 8 + 3).unwrap();
+        assert!(is_invalid(Cdb::open_verified(path, &keys)));
+        f.write_at(b"H", 2048 + 8 + 3).unwrap();
+        Cdb::open_verified(path, &keys).unwrap();
+
+        FileOptions::new().append(true).open(path).unwrap().write_all(b"junk").unwrap();
+        assert!(is_invalid(Cdb::open_verified(path, &keys)));
+
+        // So are unsigned files.
+        let unsigned = Path::new("unsigned.cdb");
+        let _rem_unsigned = RemovingPath::new(unsigned);
+        let _rem_unsigned_index = RemovingPath::new(&KeyIndex::sidecar_path(unsigned));
+        create(unsigned, None);
+        assert!(is_invalid(Cdb::open_verified(unsigned, &keys)));
+        assert!(is_invalid(Cdb::open_verified(unsigned, &[])));
+    }
+}
@@ -1,33 +1,106 @@
+extern crate cc;
+#[cfg(feature = "system")]
+extern crate pkg_config;
+
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-const TINYCDB_VERSION: &'static str = "0.78";
+const TINYCDB_VERSION: &str = "0.78";
+
+// The sources of the library itself, as listed in `LIB_SRCS` in TinyCDB's
+// Makefile.
+#[cfg(not(feature = "system"))]
+const SOURCES: &[&str] = &[
+    "cdb_init.c", "cdb_find.c", "cdb_findnext.c", "cdb_seq.c", "cdb_seek.c",
+    "cdb_unpack.c",
+    "cdb_make_add.c", "cdb_make_put.c", "cdb_make.c", "cdb_hash.c",
+];
+
+// Links against the installed library, and returns where its header is.
+#[cfg(feature = "system")]
+fn library() -> Vec<PathBuf> {
+    match pkg_config::probe_library("libcdb") {
+        Ok(lib) => lib.include_paths,
+        Err(e) => panic!(
+            "\n\nThe `system` feature is enabled, but libcdb could not be found with \
+             pkg-config:\n\n{}\n\nInstall TinyCDB's development files, or disable the \
+             `system` feature to build the bundled copy of TinyCDB {}.\n\n",
+            e, TINYCDB_VERSION,
+        ),
+    }
+}
+
+// Builds the bundled library, and returns where its header is.
+#[cfg(not(feature = "system"))]
+fn library() -> Vec<PathBuf> {
+    let tinycdb_path = Path::new("deps").join(format!("tinycdb-{}", TINYCDB_VERSION));
+    if !tinycdb_path.join("cdb.h").exists() {
+        panic!(
+            "\n\nThe bundled TinyCDB sources are missing from {}.\n\n",
+            tinycdb_path.display(),
+        );
+    }
+
+    println!("cargo:rerun-if-changed={}", tinycdb_path.display());
+
+    // Everything is built in OUT_DIR, and the sources are left untouched.
+    let mut build = cc::Build::new();
+    build.include(&tinycdb_path)
+         .define("_FILE_OFFSET_BITS", "64")
+         .warnings(false);
+    for source in SOURCES {
+        build.file(tinycdb_path.join(source));
+    }
+
+    if let Err(e) = build.try_compile("cdb") {
+        panic!("\n\nFailed to compile the bundled TinyCDB {}:\n\n{}\n\n", TINYCDB_VERSION, e);
+    }
+
+    vec![tinycdb_path]
+}
+
+// Works out the layout of TinyCDB's structures by building layout.c against
+// the same header as the library and running it, and writes it out as
+// `C_LAYOUT` for lib.rs to check its own definitions against when it is
+// compiled.  The program can't be run when cross-compiling, so the check is
+// skipped then.
+fn layout(include_paths: &[PathBuf]) {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let dest = out_dir.join("layout.rs");
+
+    if env::var("HOST").ok() != env::var("TARGET").ok() {
+        println!("cargo:warning=Not checking the layout of TinyCDB's structures when cross-compiling");
+        fs::write(&dest, "const C_LAYOUT: Option<&[usize]> = None;\n").unwrap();
+        return;
+    }
+
+    let source = Path::new("src").join("layout.c");
+    let exe = out_dir.join("cdb_layout");
+    let compiler = cc::Build::new().includes(include_paths).get_compiler();
+    let mut cmd = compiler.to_command();
+    cmd.arg(&source).arg("-o").arg(&exe);
+    match cmd.status() {
+        Ok(status) if status.success() => {},
+        res => panic!("\n\nFailed to compile {} against cdb.h: {:?}\n\n", source.display(), res),
+    }
+
+    let output = match Command::new(&exe).output() {
+        Ok(output) if output.status.success() => output,
+        res => panic!("\n\nFailed to run {}: {:?}\n\n", exe.display(), res),
+    };
+    let values: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(|v| v.to_string())
+        .collect();
+    fs::write(&dest, format!("const C_LAYOUT: Option<&[usize]> = Some(&[{}]);\n", values.join(", "))).unwrap();
+}
 
 fn main() {
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let tinycdb_folder = format!("tinycdb-{}", TINYCDB_VERSION);
-    let tinycdb_path = Path::new("deps")
-                            .join(&tinycdb_folder);
-
-    // Clean the build directory first.
-    Command::new("make").arg("-C")
-                        .arg(&tinycdb_path)
-                        .arg("clean")
-                        .status().unwrap();
-
-    // Call "make" to build the native C library
-    Command::new("make").arg("-C")
-                        .arg(&tinycdb_path)
-                        .arg("piclib")
-                        .status().unwrap();
-
-    // Copy the output file to the expected output directory.
-    let built_path = tinycdb_path.join("libcdb_pic.a");
-    let output_path = Path::new(&out_dir).join("libcdb.a");
-    fs::copy(&built_path, &output_path).unwrap();
-
-    // Tell Rust about what we link to
-    println!("cargo:rustc-flags=-L {} -l static=cdb", out_dir);
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/layout.c");
+
+    let include_paths = library();
+    layout(&include_paths);
 }
@@ -0,0 +1,48 @@
+/*
+ * Prints the layout of TinyCDB's structures as the C compiler sees it, so
+ * that it can be checked against the Rust definitions in lib.rs.  This is
+ * run by build.rs, and the order must match `LAYOUT` in lib.rs.
+ */
+
+#include <stddef.h>
+#include <stdio.h>
+#include <cdb.h>
+
+static const size_t layout[] = {
+  sizeof(struct cdb),
+  offsetof(struct cdb, cdb_fd),
+  offsetof(struct cdb, cdb_fsize),
+  offsetof(struct cdb, cdb_dend),
+  offsetof(struct cdb, cdb_mem),
+  offsetof(struct cdb, cdb_vpos),
+  offsetof(struct cdb, cdb_vlen),
+  offsetof(struct cdb, cdb_kpos),
+  offsetof(struct cdb, cdb_klen),
+
+  sizeof(struct cdb_find),
+  offsetof(struct cdb_find, cdb_cdbp),
+  offsetof(struct cdb_find, cdb_hval),
+  offsetof(struct cdb_find, cdb_htp),
+  offsetof(struct cdb_find, cdb_htab),
+  offsetof(struct cdb_find, cdb_htend),
+  offsetof(struct cdb_find, cdb_httodo),
+  offsetof(struct cdb_find, cdb_key),
+  offsetof(struct cdb_find, cdb_klen),
+
+  sizeof(struct cdb_make),
+  offsetof(struct cdb_make, cdb_fd),
+  offsetof(struct cdb_make, cdb_dpos),
+  offsetof(struct cdb_make, cdb_rcnt),
+  offsetof(struct cdb_make, cdb_buf),
+  offsetof(struct cdb_make, cdb_bpos),
+  offsetof(struct cdb_make, cdb_rec),
+
+  sizeof(enum cdb_put_mode),
+};
+
+int main(void) {
+  size_t i;
+  for (i = 0; i < sizeof(layout) / sizeof(layout[0]); i++)
+    printf("%lu\n", (unsigned long) layout[i]);
+  return 0;
+}
@@ -3,6 +3,8 @@
 
 extern crate libc;
 
+use std::mem::{offset_of, size_of};
+
 use libc::{c_int, c_uchar, c_uint, c_void};
 
 #[repr(C)]
@@ -49,6 +51,23 @@ impl cdb {
     pub fn cdb_keylen(&self) -> c_uint {
         self.cdb_klen
     }
+
+    #[inline]
+    pub fn cdb_fileno(&self) -> c_int {
+        self.cdb_fd
+    }
+
+    // Private members with no macro in cdb.h, needed to unmap the file
+    // without `cdb_free`.  Their layout is checked against cdb.h.
+    #[inline]
+    pub fn cdb_mem(&self) -> *const c_uchar {
+        self.cdb_mem
+    }
+
+    #[inline]
+    pub fn cdb_fsize(&self) -> c_uint {
+        self.cdb_fsize
+    }
 }
 
 #[repr(C)]
@@ -154,9 +173,73 @@ extern "C" {
     pub fn cdb_make_finish(cdbmp: *mut cdb_make) -> c_int;
 
     pub fn cdb_seqnext(cptr: *mut c_uint, cdbp: *mut cdb) -> c_int;
+
+    pub fn cdb_seek(fd: c_int, key: *const c_void, klen: c_uint, dlenp: *mut c_uint) -> c_int;
+    pub fn cdb_bread(fd: c_int, buf: *mut c_void, len: c_int) -> c_int;
+
+    pub fn cdb_hash(buf: *const c_void, len: c_uint) -> c_uint;
+    pub fn cdb_unpack(buf: *const c_uchar) -> c_uint;
+    pub fn cdb_pack(num: c_uint, buf: *mut c_uchar);
 }
 
-// A C macro
+/// A C macro
+///
+/// # Safety
+///
+/// `cptr` must be valid for writes.
 pub unsafe fn cdb_seqinit(cptr: *mut c_uint, _cdbp: *mut cdb) {
     *cptr = 2048;
 }
+
+// The layout of the structures as Rust sees it, in the same order as in
+// layout.c.
+const LAYOUT: [(&str, usize); 26] = [
+    ("sizeof(struct cdb)",          size_of::<cdb>()),
+    ("cdb.cdb_fd",                  offset_of!(cdb, cdb_fd)),
+    ("cdb.cdb_fsize",               offset_of!(cdb, cdb_fsize)),
+    ("cdb.cdb_dend",                offset_of!(cdb, cdb_dend)),
+    ("cdb.cdb_mem",                 offset_of!(cdb, cdb_mem)),
+    ("cdb.cdb_vpos",                offset_of!(cdb, cdb_vpos)),
+    ("cdb.cdb_vlen",                offset_of!(cdb, cdb_vlen)),
+    ("cdb.cdb_kpos",                offset_of!(cdb, cdb_kpos)),
+    ("cdb.cdb_klen",                offset_of!(cdb, cdb_klen)),
+
+    ("sizeof(struct cdb_find)",     size_of::<cdb_find>()),
+    ("cdb_find.cdb_cdbp",           offset_of!(cdb_find, cdb_cdbp)),
+    ("cdb_find.cdb_hval",           offset_of!(cdb_find, cdb_hval)),
+    ("cdb_find.cdb_htp",            offset_of!(cdb_find, cdb_htp)),
+    ("cdb_find.cdb_htab",           offset_of!(cdb_find, cdb_htab)),
+    ("cdb_find.cdb_htend",          offset_of!(cdb_find, cdb_htend)),
+    ("cdb_find.cdb_httodo",         offset_of!(cdb_find, cdb_httodo)),
+    ("cdb_find.cdb_key",            offset_of!(cdb_find, cdb_key)),
+    ("cdb_find.cdb_klen",           offset_of!(cdb_find, cdb_klen)),
+
+    ("sizeof(struct cdb_make)",     size_of::<cdb_make>()),
+    ("cdb_make.cdb_fd",             offset_of!(cdb_make, cdb_fd)),
+    ("cdb_make.cdb_dpos",           offset_of!(cdb_make, cdb_dpos)),
+    ("cdb_make.cdb_rcnt",           offset_of!(cdb_make, cdb_rcnt)),
+    ("cdb_make.cdb_buf",            offset_of!(cdb_make, cdb_buf)),
+    ("cdb_make.cdb_bpos",           offset_of!(cdb_make, cdb_bpos)),
+    ("cdb_make.cdb_rec",            offset_of!(cdb_make, cdb_rec)),
+
+    ("sizeof(enum cdb_put_mode)",   size_of::<CdbPutMode>()),
+];
+
+// `C_LAYOUT` is the layout as the C compiler sees it, written out by
+// build.rs.
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+
+// Fails the build, naming the first entry that differs, if the structures
+// above don't match cdb.h.
+const _: () = {
+    if let Some(c_layout) = C_LAYOUT {
+        assert!(c_layout.len() == LAYOUT.len(), "layout.c and LAYOUT have different lengths");
+        let mut i = 0;
+        while i < LAYOUT.len() {
+            if LAYOUT[i].1 != c_layout[i] {
+                panic!("{}", LAYOUT[i].0);
+            }
+            i += 1;
+        }
+    }
+};